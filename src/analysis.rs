@@ -0,0 +1,296 @@
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+use crate::summary::ShipEntry;
+
+/// Metrics we look for patterns in, and what to call them
+const ANALYZED_METRICS: [(&str, &str); 8] = [
+    ("damage_dealt", "damage"),
+    ("frags", "kills"),
+    ("winrate", "winrate"),
+    ("survival_rate", "survival"),
+    ("main_battery.hitrate", "main battery accuracy"),
+    ("ships_spotted", "spotting"),
+    ("capture_points", "capping"),
+    ("xp", "XP"),
+];
+
+/// Average percentile at or above which something counts as a strength
+const STRONG_PERCENTILE: f64 = 70.0;
+
+/// Average percentile at or below which something counts as a weakness
+const WEAK_PERCENTILE: f64 = 30.0;
+
+/// Ships with fewer battles say too little about the player to be included
+const MIN_SHIP_BATTLES: u32 = 20;
+
+/// A pattern needs at least this many battles behind it to be reported
+const MIN_PATTERN_BATTLES: u64 = 100;
+
+/// Combinations of a strength and a weakness worth calling out together
+const CONTRASTS: [(&str, &str, &str); 3] = [
+    (
+        "damage_dealt",
+        "survival_rate",
+        "Deals plenty of damage, but doesn't survive many battles. Staying alive longer may convert that damage into more wins.",
+    ),
+    (
+        "damage_dealt",
+        "winrate",
+        "Deals plenty of damage, but it doesn't turn into wins. Damage on the right targets, and playing the objective, matter more.",
+    ),
+    (
+        "survival_rate",
+        "damage_dealt",
+        "Survives often, but without dealing much damage. Playing a little more aggressively could help the team more.",
+    ),
+];
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub metric: String,
+    /// Set when the finding only holds for one class of ships
+    pub class: Option<String>,
+    /// Battle-weighted average percentile across the ships
+    pub percentile: f64,
+    pub battles: u64,
+    pub text: String,
+}
+
+/// Consistent strengths and weaknesses across a player's ships
+#[derive(Debug, Default, Serialize)]
+pub struct Analysis {
+    pub strengths: Vec<Finding>,
+    pub weaknesses: Vec<Finding>,
+    pub patterns: Vec<String>,
+}
+
+#[derive(Default)]
+struct WeightedPercentile {
+    total: f64,
+    battles: u64,
+}
+
+impl WeightedPercentile {
+    fn mean(&self) -> Option<f64> {
+        if self.battles < MIN_PATTERN_BATTLES {
+            return None;
+        }
+        Some(self.total / self.battles as f64)
+    }
+}
+
+fn label(metric: &str) -> &str {
+    ANALYZED_METRICS
+        .iter()
+        .find(|(key, _)| *key == metric)
+        .map(|(_, label)| *label)
+        .unwrap_or(metric)
+}
+
+fn finding(metric: &str, label: &str, class: Option<&str>, w: &WeightedPercentile) -> Finding {
+    let percentile = w.total / w.battles as f64;
+    let subject = match class {
+        Some(class) => format!("{} in {}s", label, class),
+        None => label.to_string(),
+    };
+    let mut subject = subject.chars();
+    let subject = match subject.next() {
+        Some(first) => first.to_uppercase().chain(subject).collect::<String>(),
+        None => String::new(),
+    };
+    Finding {
+        metric: metric.to_string(),
+        class: class.map(|x| x.to_string()),
+        percentile,
+        battles: w.battles,
+        text: format!(
+            "{}: better than {:.0}% of players, over {} battles",
+            subject, percentile, w.battles
+        ),
+    }
+}
+
+impl Analysis {
+    pub fn new(ships: &[ShipEntry]) -> Self {
+        let mut overall: BTreeMap<&str, WeightedPercentile> = BTreeMap::new();
+        let mut by_class: BTreeMap<(&str, &str), WeightedPercentile> = BTreeMap::new();
+        for ship in ships
+            .iter()
+            .filter(|ship| ship.stats.battles >= MIN_SHIP_BATTLES)
+        {
+            let battles = ship.stats.battles as u64;
+            for (metric, _) in ANALYZED_METRICS.iter() {
+                let percentile = match ship.percentiles.get(*metric) {
+                    Some(x) => *x,
+                    None => continue,
+                };
+                let entry = overall.entry(metric).or_default();
+                entry.total += percentile * battles as f64;
+                entry.battles += battles;
+                if let Some(info) = ship.info {
+                    let entry = by_class
+                        .entry((info.ship_type.as_str(), metric))
+                        .or_default();
+                    entry.total += percentile * battles as f64;
+                    entry.battles += battles;
+                }
+            }
+        }
+
+        let mut analysis = Self::default();
+        let strength = |w: &WeightedPercentile| w.mean().map(|x| x >= STRONG_PERCENTILE);
+        let weakness = |w: &WeightedPercentile| w.mean().map(|x| x <= WEAK_PERCENTILE);
+
+        for (metric, w) in overall.iter() {
+            if strength(w) == Some(true) {
+                analysis
+                    .strengths
+                    .push(finding(metric, label(metric), None, w));
+            } else if weakness(w) == Some(true) {
+                analysis
+                    .weaknesses
+                    .push(finding(metric, label(metric), None, w));
+            }
+        }
+
+        // Only mention a class when it stands out from the player's overall pattern
+        for ((class, metric), w) in by_class.iter() {
+            let overall = &overall[metric];
+            if strength(w) == Some(true) && strength(overall) != Some(true) {
+                analysis
+                    .strengths
+                    .push(finding(metric, label(metric), Some(class), w));
+            } else if weakness(w) == Some(true) && weakness(overall) != Some(true) {
+                analysis
+                    .weaknesses
+                    .push(finding(metric, label(metric), Some(class), w));
+            }
+        }
+
+        let is_overall = |findings: &[Finding], metric: &str| {
+            findings
+                .iter()
+                .any(|x| x.class.is_none() && x.metric == metric)
+        };
+        for (strong, weak, text) in CONTRASTS.iter() {
+            if is_overall(&analysis.strengths, strong) && is_overall(&analysis.weaknesses, weak) {
+                analysis.patterns.push(text.to_string());
+            }
+        }
+
+        analysis.strengths.sort_by(|a, b| {
+            b.percentile
+                .partial_cmp(&a.percentile)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        analysis.weaknesses.sort_by(|a, b| {
+            a.percentile
+                .partial_cmp(&b.percentile)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        analysis
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wows_data::{DetailedStats, ShipInfo};
+    use std::collections::HashMap;
+
+    fn ship_info(ship_type: &str) -> ShipInfo {
+        serde_json::from_value(serde_json::json!({
+            "description": "",
+            "price_gold": 0,
+            "ship_id_str": "PASC001",
+            "has_demo_profile": false,
+            "images": {},
+            "modules": {},
+            "modules_tree": {},
+            "nation": "usa",
+            "is_premium": false,
+            "ship_id": 1,
+            "price_credit": 0,
+            "default_profile": {
+                "mobility": null,
+                "torpedoes": null,
+                "battle_level_range_max": 10,
+                "battle_level_range_min": 8,
+            },
+            "upgrades": null,
+            "tier": 8,
+            "next_ships": {},
+            "mod_slots": 0,
+            "type": ship_type,
+            "is_special": false,
+            "name": "Test",
+        }))
+        .unwrap()
+    }
+
+    fn percentiles(values: &[(&str, f64)]) -> HashMap<String, f64> {
+        values.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn finds_consistent_patterns() {
+        let mut stats = DetailedStats::zeroed();
+        stats.battles = 200;
+        let battleship = ship_info("Battleship");
+        let cruiser = ship_info("Cruiser");
+        let bb_percentiles = percentiles(&[
+            ("damage_dealt", 85.0),
+            ("survival_rate", 15.0),
+            ("main_battery.hitrate", 10.0),
+        ]);
+        let ca_percentiles = percentiles(&[
+            ("damage_dealt", 80.0),
+            ("survival_rate", 20.0),
+            ("main_battery.hitrate", 60.0),
+        ]);
+        let ships = [
+            ShipEntry {
+                ship_id: 1,
+                info: Some(&battleship),
+                stats: &stats,
+                percentiles: &bb_percentiles,
+                reliable: true,
+            },
+            ShipEntry {
+                ship_id: 2,
+                info: Some(&cruiser),
+                stats: &stats,
+                percentiles: &ca_percentiles,
+                reliable: true,
+            },
+        ];
+        let analysis = Analysis::new(&ships);
+
+        assert_eq!(analysis.strengths.len(), 1);
+        assert_eq!(analysis.strengths[0].metric, "damage_dealt");
+        assert_eq!(analysis.strengths[0].percentile, 82.5);
+
+        // Accuracy is fine overall, but not on battleships
+        let accuracy = analysis
+            .weaknesses
+            .iter()
+            .find(|x| x.metric == "main_battery.hitrate")
+            .unwrap();
+        assert_eq!(accuracy.class.as_deref(), Some("Battleship"));
+        assert!(accuracy
+            .text
+            .starts_with("Main battery accuracy in Battleships"));
+
+        // Survival is weak everywhere, so it isn't repeated per class
+        assert_eq!(
+            analysis
+                .weaknesses
+                .iter()
+                .filter(|x| x.metric == "survival_rate")
+                .count(),
+            1
+        );
+        assert_eq!(analysis.patterns.len(), 1);
+    }
+}
@@ -1,9 +1,21 @@
+use rocket::request::FromParam;
+use rocket::FromForm;
 use serde_derive::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::time::{sleep, Duration};
+use tracing::*;
 
+use crate::error::{DroppableError, Error};
+use crate::gameparams::{Ability, GameParams, ProcessedShip, BW_UNITS_PER_KM};
 use crate::ships::ShipDb;
 
+/// How often to check whether GameParams.json has changed on disk
+const RELOAD_CHECK_SECS: u64 = 60;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ShipClass {
     Destroyer,
@@ -14,6 +26,22 @@ pub enum ShipClass {
 }
 
 impl ShipClass {
+    /// Parses either the short ("DD") or long ("Destroyer") name, ignoring case
+    pub fn parse(s: &str) -> Option<Self> {
+        [
+            Self::Destroyer,
+            Self::Cruiser,
+            Self::Battleship,
+            Self::AircraftCarrier,
+            Self::Submarine,
+        ]
+        .iter()
+        .copied()
+        .find(|class| {
+            s.eq_ignore_ascii_case(class.short()) || s.eq_ignore_ascii_case(&format!("{:?}", class))
+        })
+    }
+
     pub fn short(&self) -> &'static str {
         match self {
             Self::Destroyer => "DD",
@@ -46,24 +74,147 @@ impl std::cmp::Ord for ShipClass {
     }
 }
 
-impl std::convert::From<String> for ShipClass {
-    fn from(s: String) -> Self {
-        if s == "Cruiser" {
-            Self::Cruiser
-        } else if s == "Destroyer" {
-            Self::Destroyer
-        } else if s == "Battleship" {
-            Self::Battleship
-        } else if s == "AirCarrier" {
-            Self::AircraftCarrier
-        } else if s == "Submarine" {
-            Self::Submarine
-        } else {
-            panic!("Unknown ship type {}", s);
+impl TryFrom<&str> for ShipClass {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self, Error> {
+        match s {
+            "Cruiser" => Ok(Self::Cruiser),
+            "Destroyer" => Ok(Self::Destroyer),
+            "Battleship" => Ok(Self::Battleship),
+            "AirCarrier" => Ok(Self::AircraftCarrier),
+            "Submarine" => Ok(Self::Submarine),
+            _ => Err(Error::UnknownShipClass {
+                ship_type: s.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Consumable {
+    pub kind: String,
+    pub label: String,
+    /// Detection range against ships, for radar and hydro
+    pub range_km: Option<f32>,
+    /// Detection range against torpedoes, for hydro
+    pub torpedo_range_km: Option<f32>,
+    pub duration: f32,
+    pub cooldown: f32,
+    /// None if there's no limit
+    pub charges: Option<u32>,
+    /// Anything else worth knowing, like the smoke radius or speed boost
+    pub details: Vec<String>,
+}
+
+impl Consumable {
+    fn from(ability: &Ability) -> Self {
+        let to_km = |x: f32| x / BW_UNITS_PER_KM;
+        let mut details = vec![];
+        if let Some(radius) = ability.radius {
+            details.push(format!("{:.2}km radius", to_km(radius)));
+        }
+        if let Some(life_time) = ability.life_time {
+            details.push(format!("{:.0}s dispersion", life_time));
+        }
+        if let Some(boost) = ability.boost_coeff {
+            details.push(format!("+{:.0}% speed", boost * 100.0));
+        }
+        if let Some(regen) = ability.regeneration_hp_speed {
+            details.push(format!("{:.1}% HP/s", regen * 100.0));
+        }
+        if let Some(multiplier) = ability.bubble_damage_multiplier {
+            details.push(format!("x{} flak damage", multiplier));
+        }
+        if let Some(fighters) = ability.fighters_num {
+            details.push(format!("{} fighters", fighters));
+        }
+        Consumable {
+            kind: ability.consumable_type.clone(),
+            label: consumable_label(&ability.consumable_type).to_string(),
+            range_km: ability.dist_ship.map(to_km),
+            torpedo_range_km: ability.dist_torpedo.map(to_km),
+            duration: ability.work_time,
+            cooldown: ability.reload_time,
+            charges: u32::try_from(ability.num_consumables).ok(),
+            details,
         }
     }
 }
 
+/// The consumables that can go in one slot. Ships can only take one of the `options`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsumableSlot {
+    pub slot: u32,
+    pub options: Vec<Consumable>,
+}
+
+/// One way a ship can get radar or hydro
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DetectionConsumable {
+    pub slot: u32,
+    pub range_km: f32,
+    pub torpedo_range_km: Option<f32>,
+    pub duration: f32,
+    /// The other consumables in the same slot, which have to be given up to take this one
+    pub instead_of: Vec<String>,
+}
+
+/// Every distinct variant of the `kind` consumable the ship can take
+fn detection_variants(slots: &[ConsumableSlot], kind: &str) -> Vec<DetectionConsumable> {
+    let mut variants: Vec<DetectionConsumable> = vec![];
+    for slot in slots.iter() {
+        let instead_of: Vec<String> = slot
+            .options
+            .iter()
+            .filter(|x| x.kind != kind)
+            .map(|x| x.label.clone())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        for consumable in slot.options.iter().filter(|x| x.kind == kind) {
+            let range_km = match consumable.range_km {
+                Some(x) => x,
+                None => continue,
+            };
+            let variant = DetectionConsumable {
+                slot: slot.slot,
+                range_km,
+                torpedo_range_km: consumable.torpedo_range_km,
+                duration: consumable.duration,
+                instead_of: instead_of.clone(),
+            };
+            if !variants.contains(&variant) {
+                variants.push(variant);
+            }
+        }
+    }
+    variants
+}
+
+fn max_range(variants: &[DetectionConsumable]) -> Option<f32> {
+    variants.iter().map(|x| x.range_km).reduce(f32::max)
+}
+
+fn consumable_label(consumable_type: &str) -> &str {
+    match consumable_type {
+        "sonar" => "Hydro",
+        "rls" => "Radar",
+        "smokeGenerator" => "Smoke",
+        "airDefenseDisp" => "Defensive AA",
+        "fighter" => "Fighter",
+        "speedBoosters" => "Speed boost",
+        "regenCrew" => "Repair party",
+        "crashCrew" => "Damage control",
+        "scout" => "Spotter",
+        "torpedoReloader" => "Torpedo reload booster",
+        "artilleryBoosters" => "Main battery reload booster",
+        "hydrophone" => "Hydrophone",
+        "submarineLocator" => "Submarine surveillance",
+        x => x,
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Ship {
     pub name: String,
@@ -73,37 +224,65 @@ pub struct Ship {
     pub class: ShipClass,
     pub speed: f32,
     pub torpedoes: Option<f32>,
-    pub hydro: Option<f32>,
-    pub radar: Option<f32>,
+    pub hydro: Vec<DetectionConsumable>,
+    pub radar: Vec<DetectionConsumable>,
+    pub health: Option<f32>,
+    pub main_battery_range: Option<f32>,
+    pub main_battery_caliber: Option<f32>,
+    pub surface_detection: Option<f32>,
+    pub air_detection: Option<f32>,
     pub min_tier: u16,
     pub max_tier: u16,
     pub nation: String,
+    pub is_premium: bool,
+    pub consumable_slots: Vec<ConsumableSlot>,
+    /// How far the best radar reaches past the ship's own surface detection. Positive means the
+    /// ship can radar without being spotted.
+    pub radar_margin: Option<f32>,
+    pub hydro_margin: Option<f32>,
+    /// Data we couldn't find for this ship, so that the cheatsheet can flag it as incomplete
+    pub missing: Vec<String>,
 }
 
 impl Ship {
+    /// Builds the cheatsheet entry for a ship. Anything which is missing (GameParams data for a
+    /// brand new ship, say) is left empty and noted in `missing`, rather than failing the whole
+    /// ship.
     fn from(
         shipinfo: &crate::wows_data::ShipInfo,
-        params: &crate::gameparams::ProcessedShip,
+        params: Option<&ProcessedShip>,
         modules: &HashMap<u64, crate::wows_data::DetailedModuleInfo>,
-    ) -> Self {
-        let mut hydro = None;
-        let mut radar = None;
-        for (_slotid, slot) in params.ability_slots.iter().enumerate() {
-            for ability in slot.options.iter() {
-                //println!("{} type is {}", slotid, ability.consumable_type);
-                if ability.consumable_type == "sonar" {
-                    hydro = Some(ability.dist_ship.unwrap() / 33.333333);
-                } else if ability.consumable_type == "rls" {
-                    radar = Some(ability.dist_ship.unwrap() / 33.333333);
-                }
-            }
+    ) -> Result<Self, Error> {
+        let mut missing = vec![];
+        if params.is_none() {
+            missing.push("GameParams data".to_string());
         }
+
+        let consumable_slots: Vec<_> = params
+            .map(|params| params.ability_slots.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|slot| ConsumableSlot {
+                slot: slot.slot,
+                options: slot.options.iter().map(Consumable::from).collect(),
+            })
+            .collect();
+        let hydro = detection_variants(&consumable_slots, "sonar");
+        let radar = detection_variants(&consumable_slots, "rls");
+        let surface_detection = params.and_then(|x| x.surface_detection());
+        let margin =
+            |variants: &[DetectionConsumable]| Some(max_range(variants)? - surface_detection?);
+        let radar_margin = margin(&radar);
+        let hydro_margin = margin(&hydro);
         let mut torpedoes = None;
         for (_, module_info) in shipinfo.modules_tree.iter() {
-            let module = modules.get(&module_info.module_id).expect(&format!(
-                "Couldn't find module {} specified in ship",
-                module_info.module_id
-            ));
+            let module = match modules.get(&module_info.module_id) {
+                Some(x) => x,
+                None => {
+                    missing.push(format!("module {}", module_info.module_id));
+                    continue;
+                }
+            };
             if let Some(torpedo_spec) = &module.profile.torpedoes {
                 torpedoes = Some(
                     torpedoes
@@ -112,50 +291,580 @@ impl Ship {
                 );
             }
         }
-        Ship {
+        let profile_url = match shipinfo.images.get("contour") {
+            Some(x) => x.clone(),
+            None => {
+                missing.push("contour image".to_string());
+                String::new()
+            }
+        };
+        Ok(Ship {
             name: shipinfo.name.clone(),
             id: shipinfo.ship_id,
             tier: shipinfo.tier,
-            profile_url: shipinfo
-                .images
-                .get("contour")
-                .expect("Couldn't find contour image")
-                .clone(),
-            class: shipinfo.ship_type.clone().into(),
+            profile_url,
+            class: ShipClass::try_from(shipinfo.ship_type.as_str())?,
             speed: shipinfo
                 .default_profile
                 .mobility
                 .as_ref()
                 .map(|x| x.max_speed)
                 .unwrap_or(0.0),
-            torpedoes: torpedoes,
-            hydro: hydro,
-            radar: radar,
+            torpedoes,
+            hydro,
+            radar,
+            health: params.and_then(|x| x.max_health()),
+            main_battery_range: params.and_then(|x| x.main_battery_range()),
+            main_battery_caliber: params.and_then(|x| x.main_battery_caliber()),
+            surface_detection,
+            air_detection: params.and_then(|x| x.air_detection()),
             min_tier: shipinfo.default_profile.battle_level_range_min.unwrap_or(0),
             max_tier: shipinfo.default_profile.battle_level_range_max.unwrap_or(0),
             nation: shipinfo.nation.clone(),
+            is_premium: shipinfo.is_premium || shipinfo.is_special,
+            consumable_slots,
+            radar_margin,
+            hydro_margin,
+            missing,
+        })
+    }
+}
+
+/// Ships which ShipDb and GameParams disagree about
+#[derive(Debug, Default, Serialize)]
+pub struct MismatchReport {
+    /// In the encyclopedia, but not in GameParams (e.g. GameParams is older than the game)
+    pub missing_params: Vec<ShipRef>,
+    /// Which we couldn't build a cheatsheet entry for at all, and why
+    pub unconvertible: Vec<(ShipRef, String)>,
+    /// Ships with some data missing, and what's missing
+    pub incomplete: Vec<(ShipRef, Vec<String>)>,
+    /// How many GameParams ships aren't in the encyclopedia. Most of these are unreleased or event
+    /// ships, so this is only interesting if it suddenly changes.
+    pub params_only: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShipRef {
+    pub id: u64,
+    pub name: String,
+}
+
+/// How to slice the cheatsheet, from the page's query string
+#[derive(Debug, Default, FromForm, Serialize)]
+pub struct CheatsheetQuery {
+    /// "DD", "Destroyer", etc.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nation: Option<String>,
+    /// Only premium ships if true, only tech tree ships if false
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub premium: Option<bool>,
+    /// One of "speed", "radar", "hydro" or "torpedoes". Ships are sorted by class and name
+    /// otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+}
+
+impl CheatsheetQuery {
+    /// Treats fields left blank in the filter form as unset
+    pub fn normalized(self) -> Self {
+        let non_empty = |x: Option<String>| x.filter(|x| !x.trim().is_empty());
+        CheatsheetQuery {
+            class: non_empty(self.class),
+            nation: non_empty(self.nation),
+            premium: self.premium,
+            sort: non_empty(self.sort),
+        }
+    }
+
+    pub fn matches(&self, ship: &Ship) -> bool {
+        if let Some(class) = &self.class {
+            if ShipClass::parse(class) != Some(ship.class) {
+                return false;
+            }
+        }
+        if let Some(nation) = &self.nation {
+            if !nation.eq_ignore_ascii_case(&ship.nation) {
+                return false;
+            }
+        }
+        if let Some(premium) = self.premium {
+            if premium != ship.is_premium {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn sort(&self, ships: &mut [Ship]) {
+        let key: fn(&Ship) -> Option<f32> = match self.sort.as_deref() {
+            Some("speed") => |ship| Some(ship.speed),
+            Some("radar") => |ship| max_range(&ship.radar),
+            Some("hydro") => |ship| max_range(&ship.hydro),
+            Some("torpedoes") => |ship| ship.torpedoes,
+            _ => |_| None,
+        };
+        // Biggest first, with ships which don't have the thing at the end
+        ships.sort_by(|a, b| {
+            let by_key = match (key(a), key(b)) {
+                (Some(a), Some(b)) => b.partial_cmp(&a).unwrap_or(Ordering::Equal),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            };
+            by_key.then_with(|| (a.class, &a.name).cmp(&(b.class, &b.name)))
+        });
+    }
+
+    /// The query string to keep the same view when moving between tiers
+    pub fn to_query_string(&self) -> String {
+        match serde_urlencoded::to_string(self) {
+            Ok(query) if !query.is_empty() => format!("?{}", query),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+/// A cheatsheet download, such as `8.json` or `8.csv`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CheatsheetExport {
+    pub tier: u16,
+    pub format: ExportFormat,
+}
+
+impl<'a> FromParam<'a> for CheatsheetExport {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        let (tier, format) = match param.rsplit_once('.') {
+            Some((tier, "json")) => (tier, ExportFormat::Json),
+            Some((tier, "csv")) => (tier, ExportFormat::Csv),
+            _ => return Err(param),
+        };
+        let tier = tier.parse().map_err(|_| param)?;
+        Ok(CheatsheetExport { tier, format })
+    }
+}
+
+/// One line of the CSV export. Consumables are flattened into one summary column.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    name: &'a str,
+    id: u64,
+    tier: u64,
+    class: &'static str,
+    nation: &'a str,
+    premium: bool,
+    min_tier: u16,
+    max_tier: u16,
+    speed: f32,
+    health: Option<f32>,
+    main_battery_caliber: Option<f32>,
+    main_battery_range: Option<f32>,
+    surface_detection: Option<f32>,
+    air_detection: Option<f32>,
+    torpedoes: Option<f32>,
+    radar: Option<f32>,
+    radar_margin: Option<f32>,
+    hydro: Option<f32>,
+    hydro_margin: Option<f32>,
+    consumables: String,
+}
+
+impl<'a> CsvRow<'a> {
+    fn from(ship: &'a Ship) -> Self {
+        let consumables: Vec<_> = ship
+            .consumable_slots
+            .iter()
+            .map(|slot| {
+                let options: Vec<_> = slot.options.iter().map(|x| x.label.as_str()).collect();
+                format!("Slot {}: {}", slot.slot + 1, options.join(" OR "))
+            })
+            .collect();
+        CsvRow {
+            name: &ship.name,
+            id: ship.id,
+            tier: ship.tier,
+            class: ship.class.short(),
+            nation: &ship.nation,
+            premium: ship.is_premium,
+            min_tier: ship.min_tier,
+            max_tier: ship.max_tier,
+            speed: ship.speed,
+            health: ship.health,
+            main_battery_caliber: ship.main_battery_caliber,
+            main_battery_range: ship.main_battery_range,
+            surface_detection: ship.surface_detection,
+            air_detection: ship.air_detection,
+            torpedoes: ship.torpedoes,
+            radar: max_range(&ship.radar),
+            radar_margin: ship.radar_margin,
+            hydro: max_range(&ship.hydro),
+            hydro_margin: ship.hydro_margin,
+            consumables: consumables.join("; "),
         }
     }
 }
 
+pub fn to_csv(ships: &[Ship]) -> Result<String, csv::Error> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for ship in ships.iter() {
+        writer.serialize(CsvRow::from(ship))?;
+    }
+    let data = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Parses either the game's GameParams.data or a GameParams.json converted from it, off of the
+/// async runtime since it takes a while
+pub async fn load_gameparams(path: &str) -> Result<GameParams, Error> {
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || {
+        let file = std::io::BufReader::new(std::fs::File::open(&path)?);
+        if path.ends_with(".data") {
+            GameParams::load_data(file)
+        } else {
+            GameParams::load(file).map_err(|err| Error::GameParams { err })
+        }
+    })
+    .await
+    .expect("GameParams loader panicked")
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[derive(Clone)]
 pub struct CheatsheetDb {
     shipdb: ShipDb,
-    gameparams: crate::gameparams::GameParams,
+    gameparams: Arc<Mutex<Arc<GameParams>>>,
+    gameparams_path: String,
 }
 
 impl CheatsheetDb {
-    pub fn from(shipdb: ShipDb, gameparams: crate::gameparams::GameParams) -> Self {
-        CheatsheetDb { shipdb, gameparams }
+    pub fn from(shipdb: ShipDb, gameparams: GameParams, gameparams_path: &str) -> Self {
+        CheatsheetDb {
+            shipdb,
+            gameparams: Arc::new(Mutex::new(Arc::new(gameparams))),
+            gameparams_path: gameparams_path.to_string(),
+        }
     }
 
     pub fn enumerate_ships(&self) -> Vec<u64> {
         self.shipdb.enumerate_ships()
     }
 
-    pub fn get_ship(&self, id: u64) -> Option<Ship> {
-        let param = self.gameparams.get_ship(id).unwrap();
-        let shipinfo = self.shipdb.get_ship_info(id).unwrap();
+    fn gameparams(&self) -> Arc<GameParams> {
+        self.gameparams.lock().unwrap().clone()
+    }
+
+    pub fn get_params(&self, id: u64) -> Option<ProcessedShip> {
+        self.gameparams().get_ship(id).cloned()
+    }
+
+    /// The ships which can be matched into a `tier` battle, filtered and sorted by `query`
+    pub fn ships_for(&self, tier: u16, query: &CheatsheetQuery) -> Vec<Ship> {
+        let mut ships: Vec<_> = self
+            .enumerate_ships()
+            .into_iter()
+            .filter_map(|id| {
+                self.get_ship(id)
+                    .log_and_drop_error(|e| warn!("Leaving ship {} off the cheatsheet: {}", id, e))
+            })
+            .filter(|ship| ship.min_tier <= tier && ship.max_tier >= tier)
+            .filter(|ship| query.matches(ship))
+            .collect();
+        query.sort(&mut ships);
+        ships
+    }
+
+    pub fn get_ship(&self, id: u64) -> Result<Ship, Error> {
+        let gameparams = self.gameparams();
+        let shipinfo = self
+            .shipdb
+            .get_ship_info(id)
+            .ok_or(Error::UnknownShip { ship_id: id })?;
         let modules = self.shipdb.get_modules();
-        Some(Ship::from(&shipinfo, param, &modules))
+        Ship::from(&shipinfo, gameparams.get_ship(id), &modules)
+    }
+
+    pub fn mismatches(&self) -> MismatchReport {
+        let gameparams = self.gameparams();
+        let modules = self.shipdb.get_modules();
+        let ships = self.shipdb.get_all_info();
+
+        let mut report = MismatchReport {
+            params_only: gameparams
+                .ship_ids()
+                .filter(|id| !ships.contains_key(*id))
+                .count(),
+            ..Default::default()
+        };
+        for (id, shipinfo) in ships.iter() {
+            let ship_ref = || ShipRef {
+                id: *id,
+                name: shipinfo.name.clone(),
+            };
+            let params = gameparams.get_ship(*id);
+            if params.is_none() {
+                report.missing_params.push(ship_ref());
+            }
+            match Ship::from(shipinfo, params, &modules) {
+                Ok(ship) if !ship.missing.is_empty() => {
+                    report.incomplete.push((ship_ref(), ship.missing))
+                }
+                Ok(_) => {}
+                Err(e) => report.unconvertible.push((ship_ref(), e.to_string())),
+            }
+        }
+        report.missing_params.sort_by_key(|x| x.id);
+        report.unconvertible.sort_by_key(|x| x.0.id);
+        report.incomplete.sort_by_key(|x| x.0.id);
+        report
+    }
+
+    /// Watches GameParams.json, and swaps in the new version whenever it changes. If the new file
+    /// doesn't parse (for example, because it's still being copied in) we keep serving the old one.
+    pub async fn reload_loop(self) {
+        let mut last_modified = modified_time(&self.gameparams_path);
+        loop {
+            sleep(Duration::from_secs(RELOAD_CHECK_SECS)).await;
+
+            let modified = modified_time(&self.gameparams_path);
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            info!("{} changed, reloading", self.gameparams_path);
+            match load_gameparams(&self.gameparams_path).await {
+                Ok(gameparams) => {
+                    info!(
+                        "Reloaded {} with {} ships",
+                        self.gameparams_path,
+                        gameparams.num_ships()
+                    );
+                    *self.gameparams.lock().unwrap() = Arc::new(gameparams);
+                }
+                Err(e) => {
+                    error!(
+                        "Couldn't reload {}, keeping the previous version: {:?}",
+                        self.gameparams_path, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consumable(kind: &str, range_km: Option<f32>) -> Consumable {
+        Consumable {
+            kind: kind.to_string(),
+            label: consumable_label(kind).to_string(),
+            range_km,
+            torpedo_range_km: None,
+            duration: 20.0,
+            cooldown: 120.0,
+            charges: Some(3),
+            details: vec![],
+        }
+    }
+
+    fn ship(name: &str, class: ShipClass, speed: f32, radar: Option<f32>) -> Ship {
+        Ship {
+            name: name.to_string(),
+            id: 0,
+            tier: 8,
+            profile_url: String::new(),
+            class,
+            speed,
+            torpedoes: None,
+            hydro: vec![],
+            radar: radar
+                .map(|range_km| DetectionConsumable {
+                    slot: 2,
+                    range_km,
+                    torpedo_range_km: None,
+                    duration: 25.0,
+                    instead_of: vec![],
+                })
+                .into_iter()
+                .collect(),
+            health: None,
+            main_battery_range: None,
+            main_battery_caliber: None,
+            surface_detection: None,
+            air_detection: None,
+            min_tier: 8,
+            max_tier: 10,
+            nation: "usa".to_string(),
+            is_premium: false,
+            consumable_slots: vec![],
+            radar_margin: None,
+            hydro_margin: None,
+            missing: vec![],
+        }
+    }
+
+    fn shipinfo(ship_type: &str) -> crate::wows_data::ShipInfo {
+        serde_json::from_value(serde_json::json!({
+            "description": "",
+            "price_gold": 0,
+            "ship_id_str": "",
+            "has_demo_profile": false,
+            "images": {},
+            "modules": {},
+            "modules_tree": {
+                "123": {
+                    "name": "Hull", "is_default": true, "price_xp": 0, "price_credit": 0,
+                    "next_ships": null, "next_modules": null, "module_id": 123, "type": "Hull",
+                    "module_id_str": "PAUH123",
+                },
+            },
+            "nation": "usa",
+            "is_premium": false,
+            "ship_id": 1,
+            "price_credit": 0,
+            "default_profile": {
+                "mobility": null,
+                "torpedoes": null,
+                "battle_level_range_max": 10,
+                "battle_level_range_min": 8,
+            },
+            "upgrades": null,
+            "tier": 8,
+            "next_ships": {},
+            "mod_slots": 4,
+            "type": ship_type,
+            "is_special": false,
+            "name": "Brand New Ship",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn incomplete_ships_are_flagged_instead_of_panicking() {
+        let ship = Ship::from(&shipinfo("Cruiser"), None, &HashMap::new()).unwrap();
+        assert_eq!(
+            ship.missing,
+            vec!["GameParams data", "module 123", "contour image"]
+        );
+        assert_eq!(ship.health, None);
+
+        match Ship::from(&shipinfo("Hovercraft"), None, &HashMap::new()) {
+            Err(Error::UnknownShipClass { ship_type }) => assert_eq!(ship_type, "Hovercraft"),
+            other => panic!("Expected an unknown class, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn query_filters_and_sorts() {
+        let mut ships = vec![
+            ship("Baltimore", ShipClass::Cruiser, 32.5, Some(9.9)),
+            ship("Benson", ShipClass::Destroyer, 36.5, None),
+            ship("Cleveland", ShipClass::Cruiser, 32.5, Some(11.1)),
+            ship("Seattle", ShipClass::Cruiser, 33.0, None),
+        ];
+        ships[3].is_premium = true;
+
+        let query = CheatsheetQuery {
+            class: Some("cr".to_string()),
+            nation: Some("".to_string()),
+            premium: Some(false),
+            sort: Some("radar".to_string()),
+        }
+        .normalized();
+        let mut matching: Vec<_> = ships.drain(..).filter(|x| query.matches(x)).collect();
+        query.sort(&mut matching);
+        let names: Vec<_> = matching.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["Cleveland", "Baltimore"]);
+        assert_eq!(
+            query.to_query_string(),
+            "?class=cr&premium=false&sort=radar"
+        );
+        assert_eq!(CheatsheetQuery::default().to_query_string(), "");
+    }
+
+    #[test]
+    fn exports_parse_and_serialize() {
+        assert_eq!(
+            CheatsheetExport::from_param("8.csv"),
+            Ok(CheatsheetExport {
+                tier: 8,
+                format: ExportFormat::Csv
+            })
+        );
+        assert!(CheatsheetExport::from_param("8").is_err());
+        assert!(CheatsheetExport::from_param("eight.json").is_err());
+
+        let ships = vec![ship("Cleveland", ShipClass::Cruiser, 32.5, Some(11.1))];
+        let csv = to_csv(&ships).unwrap();
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().starts_with("name,id,tier,class,"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("Cleveland,0,8,CR,usa,false,"));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn detection_variants_keep_alternatives() {
+        let slots = vec![
+            ConsumableSlot {
+                slot: 0,
+                options: vec![consumable("crashCrew", None)],
+            },
+            ConsumableSlot {
+                slot: 2,
+                options: vec![
+                    consumable("sonar", Some(5.0)),
+                    consumable("rls", Some(9.9)),
+                    consumable("crashCrew", None),
+                    consumable("rls", Some(9.9)),
+                    consumable("sonar", Some(5.0)),
+                ],
+            },
+            ConsumableSlot {
+                slot: 3,
+                options: vec![consumable("rls", Some(11.1))],
+            },
+        ];
+
+        let radar = detection_variants(&slots, "rls");
+        assert_eq!(
+            radar
+                .iter()
+                .map(|x| (x.slot, x.range_km, x.instead_of.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    2,
+                    9.9,
+                    vec!["Damage control".to_string(), "Hydro".to_string()]
+                ),
+                (3, 11.1, vec![]),
+            ]
+        );
+
+        let hydro = detection_variants(&slots, "sonar");
+        assert_eq!(hydro.len(), 1);
+        assert_eq!(
+            hydro[0].instead_of,
+            vec!["Damage control".to_string(), "Radar".to_string()]
+        );
     }
 }
@@ -1,7 +1,9 @@
+use futures::TryStreamExt;
 use itertools::*;
 use mongodb::bson::doc;
 use serde_derive::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use tokio::sync::watch;
 use tracing::*;
 
 use crate::error::*;
@@ -18,48 +20,278 @@ pub struct DetailedStatRecord {
     pub retrieved: chrono::DateTime<chrono::Utc>,
 }
 
+/// Where the poller was in its sweep of the realm when it was last shut down. Restoring this on
+/// boot lets the sweep continue from where it was instead of starting over at "aaa".
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PollerCheckpoint {
+    /// The first prefix which has not been listed yet
+    pub next_prefix: Option<String>,
+    /// Batches of players which were listed, but whose stats have not been retrieved yet
+    pub pending_batches: Vec<Vec<PlayerRecord>>,
+    pub saved: chrono::DateTime<chrono::Utc>,
+}
+
+const PREFIX_ALPHABET: [char; 37] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's',
+    't', 'u', 'v', 'w', 'x', 'y', 'z', '_', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+/// Every 3-character username prefix, in the order the poller sweeps them
+fn all_prefixes() -> Vec<String> {
+    (0..3)
+        .map(|_| PREFIX_ALPHABET.iter())
+        .multi_cartesian_product()
+        .map(|prefix| prefix.into_iter().collect())
+        .collect()
+}
+
+/// Resolves once shutdown has been requested (or the sender has gone away)
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn load_checkpoint(database: &mongodb::Database) -> Option<PollerCheckpoint> {
+    let collection = database.collection::<PollerCheckpoint>("pollerstate");
+    collection
+        .find_one(None, None)
+        .await
+        .log_and_drop_error(|e| {
+            error!("Couldn't load poller checkpoint, error {:?}", e);
+        })
+        .flatten()
+}
+
+async fn save_checkpoint(database: &mongodb::Database, checkpoint: &PollerCheckpoint) {
+    let collection = database.collection::<PollerCheckpoint>("pollerstate");
+    let options = mongodb::options::ReplaceOptions::builder()
+        .upsert(true)
+        .build();
+    collection
+        .replace_one(doc! {}, checkpoint, options)
+        .await
+        .log_and_drop_error(|e| {
+            error!("Couldn't save poller checkpoint, error {:?}", e);
+        });
+}
+
+/// Retrieves and stores the stats for a single player, keeping the histograms up to date
+pub async fn update_player_stats(
+    client: &WowsClient,
+    database: &mongodb::Database,
+    histograms: &Arc<StatsHistogram>,
+    player: &PlayerRecord,
+) {
+    let stats = match client.get_detailed_stats(player.account_id).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!(
+                "Got an error {:?} retrieving detailed stats for player {}",
+                e, player.account_id
+            );
+            return;
+        }
+    };
+
+    let stats = match stats.into_iter().next() {
+        Some((_player_id, Some(stats))) => stats,
+        _ => {
+            return;
+        }
+    };
+
+    let retrieved = chrono::Utc::now();
+    let stats: Vec<DetailedStatRecord> = stats
+        .iter()
+        .map(|stat| DetailedStatRecord {
+            pvp: stat.pvp.clone(),
+            account_id: stat.account_id,
+            ship_id: stat.ship_id,
+            battles: stat.battles,
+            retrieved,
+        })
+        .collect();
+
+    let collection = database.collection::<DetailedStatRecord>("playerstats");
+
+    // The player's previous stats, which need taking back out of the histograms. Without them we
+    // can't tell what to take back, so leave the account alone until the next time around.
+    let previous: Vec<DetailedStatRecord> = match collection
+        .find(doc! {"account_id": player.account_id as i64}, None)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(previous) => previous,
+            Err(e) => {
+                error!(
+                    "Couldn't read previous stats for account_id={}, error {:?}",
+                    player.account_id, e
+                );
+                return;
+            }
+        },
+        Err(e) => {
+            error!(
+                "Couldn't read previous stats for account_id={}, error {:?}",
+                player.account_id, e
+            );
+            return;
+        }
+    };
+
+    // TODO: This is a race condition, if a query for this account comes in between
+    // the delete and the insert. This should be an upsert.
+    if let Err(e) = collection
+        .delete_many(doc! {"account_id": player.account_id as i64}, None)
+        .await
+    {
+        error!(
+            "Couldn't delete statistics for account_id={}, error {:?}",
+            player.account_id, e
+        );
+        return;
+    }
+    if !stats.is_empty() {
+        if let Err(e) = collection.insert_many(&stats, None).await {
+            error!(
+                "Couldn't insert stats for account_id={}, error {:?}",
+                player.account_id, e
+            );
+            // The previous stats are gone from the database, so they go from the histograms too
+            histograms.update_account(player.account_id, &previous, &[]);
+            return;
+        }
+    }
+
+    // Only now that the database holds the new stats do the histograms follow it
+    histograms.update_account(player.account_id, &previous, &stats);
+}
+
+/// Looks up a player who hasn't been reached by the poller yet, and retrieves their stats.
+pub async fn lookup_player(
+    client: &WowsClient,
+    database: &mongodb::Database,
+    histograms: &Arc<StatsHistogram>,
+    nickname: &str,
+) -> Option<PlayerRecord> {
+    let player = client
+        .find_player(nickname)
+        .await
+        .log_and_drop_error(|e| {
+            error!("Error looking up player {} on WoWS API: {:?}", nickname, e);
+        })??;
+    let player = PlayerRecord {
+        nickname: player.nickname.to_lowercase(),
+        account_id: player.account_id,
+    };
+
+    // Upsert, so that two page loads racing to look up the same player leave one record behind
+    let collection = database.collection::<PlayerRecord>("playerids");
+    let options = mongodb::options::UpdateOptions::builder()
+        .upsert(true)
+        .build();
+    collection
+        .update_one(
+            doc! { "account_id": player.account_id as i64 },
+            doc! { "$set": { "nickname": player.nickname.clone() } },
+            options,
+        )
+        .await
+        .log_and_drop_error(|e| {
+            error!("Error adding player record to mongo: {:?}", e);
+        });
+    update_player_stats(client, database, histograms, &player).await;
+    Some(player)
+}
+
+/// Sweeps the realm forever, until `shutdown` is set. Once it is, any in-progress work is finished
+/// and the position in the sweep is saved so that the next call resumes from there.
 pub async fn poller(
     client: &WowsClient,
     database: mongodb::Database,
-    histograms: Arc<Mutex<StatsHistogram>>,
+    histograms: Arc<StatsHistogram>,
+    mut shutdown: watch::Receiver<bool>,
 ) {
+    let prefixes = all_prefixes();
+    let checkpoint = load_checkpoint(&database).await;
+    let (start_index, pending_batches) = match checkpoint {
+        Some(checkpoint) => {
+            let start_index = checkpoint
+                .next_prefix
+                .and_then(|next| prefixes.iter().position(|prefix| *prefix == next))
+                .unwrap_or(0);
+            info!(
+                "Resuming poller at prefix {} with {} pending player batches from checkpoint saved {}",
+                prefixes[start_index],
+                checkpoint.pending_batches.len(),
+                checkpoint.saved
+            );
+            (start_index, checkpoint.pending_batches)
+        }
+        None => (0, vec![]),
+    };
+
     let (alphabet_sender, alphabet_receiver) = async_channel::bounded(256);
 
-    let x = tokio::spawn(async move {
-        let alphabet = [
-            'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q',
-            'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '_', '0', '1', '2', '3', '4', '5', '6',
-            '7', '8', '9',
-        ];
-        loop {
-            for prefix in (0..3).map(|_| alphabet.iter()).multi_cartesian_product() {
-                let prefix: String = prefix.iter().map(|c| *c).collect();
-                alphabet_sender.send(prefix).await.log_and_drop_error(|e| {
-                    error!("Couldn't send prefix through pipe, error {:?}", e);
-                });
+    let generator = {
+        let mut shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let mut index = start_index;
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = wait_for_shutdown(&mut shutdown) => {
+                        // This prefix never made it to the workers
+                        return prefixes[index].clone();
+                    }
+                    result = alphabet_sender.send(prefixes[index].clone()) => {
+                        result.log_and_drop_error(|e| {
+                            error!("Couldn't send prefix through pipe, error {:?}", e);
+                        });
+                    }
+                }
+                index = (index + 1) % prefixes.len();
             }
-        }
-    });
+        })
+    };
+
+    // Make sure there's room to put all of the resumed batches back in line
+    let (player_sender, player_receiver) = async_channel::bounded(pending_batches.len().max(1024));
+    for batch in pending_batches.into_iter() {
+        player_sender.try_send(batch).log_and_drop_error(|e| {
+            error!("Couldn't resume pending player batch, error: {:?}", e);
+        });
+    }
 
     // Have some workers to get the players for each prefix
-    let (player_sender, player_receiver) = async_channel::bounded(1024);
+    let mut prefix_workers = vec![];
     for _ in 0..10 {
         let client = client.fork();
         let player_sender = player_sender.clone();
         let alphabet_receiver = alphabet_receiver.clone();
         let database = database.clone();
-        tokio::spawn(async move {
-            while let Ok(prefix) = alphabet_receiver.recv().await {
-                let players = client.list_players(&prefix).await.map_or_else(
-                    |e| {
-                        error!(
-                            "Error listing players on WoWS API for prefix {}: {:?}",
-                            prefix, e
-                        );
-                        vec![]
+        let mut shutdown = shutdown.clone();
+        prefix_workers.push(tokio::spawn(async move {
+            loop {
+                let prefix = tokio::select! {
+                    biased;
+                    _ = wait_for_shutdown(&mut shutdown) => break,
+                    prefix = alphabet_receiver.recv() => match prefix {
+                        Ok(prefix) => prefix,
+                        Err(_) => break,
                     },
-                    |players| players,
-                );
+                };
+
+                let players = client.list_players(&prefix).await.unwrap_or_else(|e| {
+                    error!(
+                        "Error listing players on WoWS API for prefix {}: {:?}",
+                        prefix, e
+                    );
+                    vec![]
+                });
 
                 // Lowercase the usernames
                 let players: Vec<PlayerRecord> = players
@@ -71,7 +303,7 @@ pub async fn poller(
                     .collect();
 
                 // Send the players, if they exist
-                if players.len() > 0 {
+                if !players.is_empty() {
                     let collection = database.collection::<PlayerRecord>("playerids");
                     for player in players.iter() {
                         collection
@@ -103,92 +335,107 @@ pub async fn poller(
                             });
                     }*/
 
-                    player_sender.send(players).await.log_and_drop_error(|e| {
-                        error!("Couldn't send player list, error: {:?}", e);
-                    });
+                    tokio::select! {
+                        biased;
+                        _ = wait_for_shutdown(&mut shutdown) => {
+                            // The stats workers may have stopped, so hand the batch back instead
+                            return Some(players);
+                        }
+                        result = player_sender.send(players.clone()) => {
+                            result.log_and_drop_error(|e| {
+                                error!("Couldn't send player list, error: {:?}", e);
+                            });
+                        }
+                    }
                 } else {
                     debug!("No players for prefix {}", prefix);
                 }
             }
-        });
+            None
+        }));
     }
 
     // Have some workers to get detailed stats for the players
+    let mut stats_workers = vec![];
     for _ in 0..10 {
         let player_receiver = player_receiver.clone();
         let client = client.fork();
         let database = database.clone();
         let histograms = histograms.clone();
-        tokio::spawn(async move {
-            while let Ok(players) = player_receiver.recv().await {
-                //println!("Got {} players!", players.len());
-                for player in players.iter() {
-                    match client.get_detailed_stats(player.account_id).await {
-                        Ok(stats) => {
-                            //println!("Got stats for player {}", player.nickname);
-
-                            if let Some((_player_id, stats)) = stats.iter().next() {
-                                if let Some(stats) = stats {
-                                    let stats: Vec<DetailedStatRecord> = stats
-                                        .iter()
-                                        .map(|stat| DetailedStatRecord {
-                                            pvp: stat.pvp.clone(),
-                                            account_id: stat.account_id,
-                                            ship_id: stat.ship_id,
-                                            battles: stat.battles,
-                                            retrieved: chrono::Utc::now(),
-                                        })
-                                        .collect();
-
-                                    // Update the histograms
-                                    stats.iter().for_each(|stat| {
-                                        let mut histograms = histograms.lock().unwrap();
-                                        histograms.increment(stat.ship_id, &stat.pvp);
-                                    });
-
-                                    let collection =
-                                        database.collection::<DetailedStatRecord>("playerstats");
-
-                                    // TODO: This is a race condition, if a query for this account comes in between
-                                    // the delete and the insert. This should be an upsert.
-                                    collection
-                                        .delete_many(
-                                            doc! {"account_id": player.account_id as i64},
-                                            None,
-                                        )
-                                        .await
-                                        .log_and_drop_error(|e| {
-                                            error!(
-                                                "Couldn't delete statistics for account_id={}, error {:?}",
-                                                player.account_id, e
-                                            );
-                                        });
-                                    if stats.len() > 0 {
-                                        collection
-                                            .insert_many(stats, None)
-                                            .await
-                                            .log_and_drop_error(|e| {
-                                                error!(
-                                                    "Couldn't insert stats for account_id={}, error {:?}",
-                                                    player.account_id, e
-                                                );
-                                            });
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!(
-                                "Got an error {:?} retrieving detailed stats for player {}",
-                                e, player.account_id
-                            );
-                        }
+        let mut shutdown = shutdown.clone();
+        stats_workers.push(tokio::spawn(async move {
+            loop {
+                let players: Vec<PlayerRecord> = tokio::select! {
+                    biased;
+                    _ = wait_for_shutdown(&mut shutdown) => break,
+                    players = player_receiver.recv() => match players {
+                        Ok(players) => players,
+                        Err(_) => break,
+                    },
+                };
+
+                for (i, player) in players.iter().enumerate() {
+                    if *shutdown.borrow() {
+                        return Some(players[i..].to_vec());
                     }
+                    update_player_stats(&client, &database, &histograms, player).await;
                 }
             }
-        });
+            None
+        }));
     }
 
-    // Go forever
-    x.await.expect("Prefix generator should not have exited!");
+    // Go until we're told to stop
+    wait_for_shutdown(&mut shutdown).await;
+    info!("Shutting down poller, waiting for in-flight requests to finish");
+
+    let mut next_prefix = generator
+        .await
+        .expect("Prefix generator should not have panicked!");
+
+    let mut pending_batches = vec![];
+    for worker in prefix_workers.into_iter().chain(stats_workers) {
+        match worker.await {
+            Ok(Some(batch)) => pending_batches.push(batch),
+            Ok(None) => {}
+            Err(e) => {
+                error!("Poller worker failed during shutdown, error {:?}", e);
+            }
+        }
+    }
+
+    // Anything still queued up was never started on
+    if let Ok(prefix) = alphabet_receiver.try_recv() {
+        next_prefix = prefix;
+    }
+    while let Ok(batch) = player_receiver.try_recv() {
+        pending_batches.push(batch);
+    }
+
+    info!(
+        "Saving poller checkpoint at prefix {} with {} pending player batches",
+        next_prefix,
+        pending_batches.len()
+    );
+    let checkpoint = PollerCheckpoint {
+        next_prefix: Some(next_prefix),
+        pending_batches,
+        saved: chrono::Utc::now(),
+    };
+    save_checkpoint(&database, &checkpoint).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::all_prefixes;
+
+    #[test]
+    fn prefixes_cover_alphabet_in_order() {
+        let prefixes = all_prefixes();
+        assert_eq!(prefixes.len(), 37 * 37 * 37);
+        assert_eq!(prefixes[0], "aaa");
+        assert_eq!(prefixes[1], "aab");
+        assert_eq!(prefixes[37], "aba");
+        assert_eq!(prefixes[prefixes.len() - 1], "999");
+    }
 }
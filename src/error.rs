@@ -21,19 +21,39 @@ pub enum Error {
         url: String,
         params: Vec<(String, String)>,
     },
+    #[error("No usable API keys")]
+    NoHealthyApiKeys {
+        url: String,
+        params: Vec<(String, String)>,
+    },
     #[error("Empty detailed stats")]
     DetailedStats {
         url: String,
         params: Vec<(String, String)>,
         status: String,
-        meta: Option<crate::wows_data::GenericReplyMeta>,
-        error: Option<crate::wows_data::GenericReplyError>,
+        meta: Option<Box<crate::wows_data::GenericReplyMeta>>,
+        error: Option<Box<crate::wows_data::GenericReplyError>>,
     },
     #[error("Error parsing response")]
     Serde {
         #[from]
         err: serde_json::Error,
     },
+    #[error("Malformed GameParams: {err}")]
+    GameParams { err: serde_json::Error },
+    #[error("Couldn't decode pickle data at offset {offset}: {reason}")]
+    Unpickle { offset: usize, reason: String },
+    #[error("Couldn't read setting {key}: {err}")]
+    Settings {
+        key: String,
+        err: config::ConfigError,
+    },
+    #[error("Couldn't load metric definitions: {err}")]
+    Metrics { err: config::ConfigError },
+    #[error("Unknown ship type {ship_type}")]
+    UnknownShipClass { ship_type: String },
+    #[error("Ship {ship_id} isn't in the encyclopedia")]
+    UnknownShip { ship_id: u64 },
     #[error("Error performing file IO")]
     Io {
         #[from]
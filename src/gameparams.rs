@@ -1,9 +1,16 @@
-use serde_derive::Deserialize;
-use serde_json::Result;
+use serde::de::{Deserialize as _, Deserializer as _, Error as _, MapAccess, Visitor};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use serde_json::Value;
 use std::collections::HashMap;
+use tracing::*;
 
-#[derive(Debug, Clone, Deserialize)]
+use crate::error::Error;
+
+/// GameParams expresses some distances (consumables, torpedoes) in units of 30m
+pub const BW_UNITS_PER_KM: f32 = 33.333333;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Ability {
     #[serde(rename = "consumableType")]
     pub consumable_type: String,
@@ -24,6 +31,28 @@ pub struct Ability {
 
     #[serde(rename = "workTime")]
     pub work_time: f32,
+
+    /// Smoke screen radius, in BW units
+    pub radius: Option<f32>,
+
+    /// How long a smoke screen lingers
+    #[serde(rename = "lifeTime")]
+    pub life_time: Option<f32>,
+
+    /// Speed boost, as a multiplier of the ship's max speed
+    #[serde(rename = "boostCoeff")]
+    pub boost_coeff: Option<f32>,
+
+    /// Repair party healing, as a fraction of max HP per second
+    #[serde(rename = "regenerationHPSpeed")]
+    pub regeneration_hp_speed: Option<f32>,
+
+    /// Defensive AA's multiplier on flak damage
+    #[serde(rename = "bubbleDamageMultiplier")]
+    pub bubble_damage_multiplier: Option<f32>,
+
+    #[serde(rename = "fightersNum")]
+    pub fighters_num: Option<u32>,
 }
 
 #[derive(Deserialize)]
@@ -39,46 +68,518 @@ struct Ship {
     pub level: u32,
     #[serde(rename = "ShipAbilities")]
     pub abilities: Option<HashMap<String, ShipAbility>>,
+    #[serde(rename = "ShipUpgradeInfo")]
+    pub upgrade_info: Option<HashMap<String, Value>>,
+}
+
+/// A shell or torpedo, which are top-level "Projectile" entries
+#[derive(Debug, Clone, Deserialize)]
+struct Projectile {
+    #[serde(rename = "ammoType")]
+    pub ammo_type: String,
+    #[serde(rename = "alphaDamage")]
+    pub alpha_damage: f32,
+    #[serde(rename = "bulletSpeed")]
+    pub bullet_speed: Option<f32>,
+    #[serde(rename = "burnProb")]
+    pub burn_prob: Option<f32>,
+    #[serde(rename = "maxDist")]
+    pub max_dist: Option<f32>,
+    pub speed: Option<f32>,
+    #[serde(rename = "visibilityFactor")]
+    pub visibility_factor: Option<f32>,
 }
 
+#[derive(Deserialize)]
+struct Modernization {
+    pub name: String,
+    pub slot: i32,
+    #[serde(default)]
+    pub shiplevel: Vec<u32>,
+    #[serde(default)]
+    pub shiptype: Vec<String>,
+    #[serde(default)]
+    pub nation: Vec<String>,
+    #[serde(default)]
+    pub ships: Vec<String>,
+    #[serde(default)]
+    pub excludes: Vec<String>,
+}
+
+impl Modernization {
+    fn applies_to(&self, ship: &str, level: u32, species: &str, nation: &str) -> bool {
+        if self.slot < 0 || self.excludes.iter().any(|x| x == ship) {
+            return false;
+        }
+        if self.ships.iter().any(|x| x == ship) {
+            return true;
+        }
+        // Modernizations which only list specific ships don't apply to anyone else
+        if self.shiplevel.is_empty() && self.shiptype.is_empty() && self.nation.is_empty() {
+            return false;
+        }
+        (self.shiplevel.is_empty() || self.shiplevel.contains(&level))
+            && (self.shiptype.is_empty() || self.shiptype.iter().any(|x| x == species))
+            && (self.nation.is_empty() || self.nation.iter().any(|x| x == nation))
+    }
+}
+
+#[derive(Deserialize)]
+struct RawHull {
+    pub health: f32,
+    #[serde(rename = "maxSpeed")]
+    pub max_speed: f32,
+    #[serde(rename = "visibilityFactor")]
+    pub visibility_factor: f32,
+    #[serde(rename = "visibilityFactorByPlane")]
+    pub visibility_factor_by_plane: f32,
+}
+
+#[derive(Deserialize)]
+struct RawGun {
+    #[serde(rename = "ammoList")]
+    pub ammo_list: Vec<String>,
+    #[serde(rename = "barrelDiameter")]
+    pub barrel_diameter: f32,
+    #[serde(rename = "numBarrels")]
+    pub num_barrels: f32,
+    #[serde(rename = "shotDelay")]
+    pub shot_delay: f32,
+}
+
+#[derive(Deserialize)]
+struct RawAura {
+    #[serde(rename = "areaDamage")]
+    pub area_damage: f32,
+    #[serde(rename = "areaDamagePeriod")]
+    pub area_damage_period: f32,
+    #[serde(rename = "minDistance", default)]
+    pub min_distance: f32,
+    #[serde(rename = "maxDistance")]
+    pub max_distance: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct AbilitySlot {
+    pub slot: u32,
     pub options: Vec<Ability>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct Shell {
+    pub name: String,
+    pub ammo_type: String,
+    pub alpha_damage: f32,
+    pub speed: Option<f32>,
+    pub fire_chance: Option<f32>,
+}
+
+/// A group of identical gun mounts
+#[derive(Debug, Clone, Serialize)]
+pub struct Guns {
+    pub caliber_mm: f32,
+    pub barrels_per_mount: u32,
+    pub mounts: u32,
+    pub reload_time: f32,
+    pub shells: Vec<Shell>,
+}
+
+/// One artillery module (main battery or secondaries)
+#[derive(Debug, Clone, Serialize)]
+pub struct Artillery {
+    pub range_km: f32,
+    pub guns: Vec<Guns>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Torpedo {
+    pub name: String,
+    pub alpha_damage: f32,
+    pub range_km: f32,
+    pub speed_knots: f32,
+    pub detection_km: Option<f32>,
+}
+
+/// A group of identical torpedo launchers
+#[derive(Debug, Clone, Serialize)]
+pub struct TorpedoLaunchers {
+    pub tubes_per_launcher: u32,
+    pub launchers: u32,
+    pub reload_time: f32,
+    pub torpedoes: Vec<Torpedo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Hull {
+    pub health: f32,
+    pub max_speed: f32,
+    pub surface_detection_km: f32,
+    pub air_detection_km: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AaAura {
+    pub name: String,
+    pub damage_per_second: f32,
+    pub min_range_km: f32,
+    pub max_range_km: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpgradeSlot {
+    pub slot: u32,
+    pub options: Vec<String>,
+}
+
+/// A ship, with each of its modules. Ships with researchable modules have one entry per option in
+/// `hulls`, `main_battery`, etc.
+#[derive(Clone, Serialize)]
 pub struct ProcessedShip {
     pub id: u64,
     pub name: String,
     pub level: u32,
+    pub nation: String,
+    pub species: String,
     pub ability_slots: Vec<AbilitySlot>,
+    pub hulls: Vec<Hull>,
+    pub main_battery: Vec<Artillery>,
+    pub secondaries: Vec<Artillery>,
+    pub torpedoes: Vec<Vec<TorpedoLaunchers>>,
+    pub air_defense: Vec<Vec<AaAura>>,
+    pub upgrade_slots: Vec<UpgradeSlot>,
+}
+
+/// The names of all of the components of the given type (e.g. "hull", "artillery") across every
+/// module configuration the ship has.
+fn component_names(upgrade_info: &Option<HashMap<String, Value>>, component: &str) -> Vec<String> {
+    let mut names: Vec<String> = upgrade_info
+        .iter()
+        .flat_map(|upgrades| upgrades.values())
+        .filter_map(|upgrade| upgrade.get("components")?.get(component)?.as_array())
+        .flatten()
+        .filter_map(|name| name.as_str().map(|x| x.to_string()))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Sub-objects of a component whose typeinfo type matches `object_type`
+fn typed_children<'a>(
+    component: &'a Value,
+    object_type: &'a str,
+) -> impl Iterator<Item = &'a Value> + 'a {
+    component
+        .as_object()
+        .into_iter()
+        .flat_map(|x| x.values())
+        .filter(move |child| {
+            child
+                .get("typeinfo")
+                .and_then(|typeinfo| typeinfo.get("type"))
+                .and_then(|x| x.as_str())
+                == Some(object_type)
+        })
+}
+
+/// Groups identical guns together, returning (gun, count) pairs
+fn group_guns(component: &Value) -> Vec<(RawGun, u32)> {
+    let mut groups: Vec<(RawGun, u32)> = vec![];
+    for gun in typed_children(component, "Gun") {
+        let gun = match RawGun::deserialize(gun) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        match groups.iter_mut().find(|(g, _)| {
+            g.ammo_list == gun.ammo_list
+                && g.barrel_diameter == gun.barrel_diameter
+                && g.num_barrels == gun.num_barrels
+                && g.shot_delay == gun.shot_delay
+        }) {
+            Some((_, count)) => *count += 1,
+            None => groups.push((gun, 1)),
+        }
+    }
+    groups
+}
+
+/// An artillery module, before its shells are looked up
+struct RawArtillery {
+    range_km: f32,
+    guns: Vec<(RawGun, u32)>,
+}
+
+impl RawArtillery {
+    fn parse(component: &Value) -> Self {
+        let range_km = component
+            .get("maxDist")
+            .and_then(|x| x.as_f64())
+            .unwrap_or(0.0) as f32
+            / 1000.0;
+        Self {
+            range_km,
+            guns: group_guns(component),
+        }
+    }
+}
+
+fn resolve_artillery(raw: RawArtillery, projectiles: &HashMap<String, Projectile>) -> Artillery {
+    let guns = raw
+        .guns
+        .into_iter()
+        .map(|(gun, mounts)| Guns {
+            caliber_mm: gun.barrel_diameter * 1000.0,
+            barrels_per_mount: gun.num_barrels as u32,
+            mounts,
+            reload_time: gun.shot_delay,
+            shells: gun
+                .ammo_list
+                .iter()
+                .filter_map(|name| {
+                    let projectile = projectiles.get(name)?;
+                    Some(Shell {
+                        name: name.clone(),
+                        ammo_type: projectile.ammo_type.clone(),
+                        alpha_damage: projectile.alpha_damage,
+                        speed: projectile.bullet_speed,
+                        fire_chance: projectile.burn_prob,
+                    })
+                })
+                .collect(),
+        })
+        .collect();
+    Artillery {
+        range_km: raw.range_km,
+        guns,
+    }
+}
+
+fn resolve_torpedoes(
+    launchers: Vec<(RawGun, u32)>,
+    projectiles: &HashMap<String, Projectile>,
+) -> Vec<TorpedoLaunchers> {
+    launchers
+        .into_iter()
+        .map(|(launcher, launchers)| TorpedoLaunchers {
+            tubes_per_launcher: launcher.num_barrels as u32,
+            launchers,
+            reload_time: launcher.shot_delay,
+            torpedoes: launcher
+                .ammo_list
+                .iter()
+                .filter_map(|name| {
+                    let projectile = projectiles.get(name)?;
+                    Some(Torpedo {
+                        name: name.clone(),
+                        alpha_damage: projectile.alpha_damage,
+                        range_km: projectile.max_dist? / BW_UNITS_PER_KM,
+                        speed_knots: projectile.speed?,
+                        detection_km: projectile.visibility_factor,
+                    })
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn parse_auras(component: &Value) -> Vec<AaAura> {
+    let mut auras: Vec<AaAura> = component
+        .as_object()
+        .into_iter()
+        .flat_map(|x| x.iter())
+        .filter_map(|(name, aura)| {
+            let aura = RawAura::deserialize(aura).ok()?;
+            Some(AaAura {
+                name: name.clone(),
+                damage_per_second: aura.area_damage / aura.area_damage_period.max(0.001),
+                min_range_km: aura.min_distance / 1000.0,
+                max_range_km: aura.max_distance / 1000.0,
+            })
+        })
+        .collect();
+    auras.sort_by(|a, b| b.max_range_km.total_cmp(&a.max_range_km));
+    auras
+}
+
+/// A ship's modules as read from its entry, before its consumables, shells and torpedoes (which
+/// may come later in the file) are looked up. Only the components we use are kept.
+struct RawShip {
+    typeinfo: TypeInfo,
+    id: u64,
+    name: String,
+    level: u32,
+    abilities: Option<HashMap<String, ShipAbility>>,
+    hulls: Vec<Hull>,
+    main_battery: Vec<RawArtillery>,
+    secondaries: Vec<RawArtillery>,
+    torpedoes: Vec<Vec<(RawGun, u32)>>,
+    air_defense: Vec<Vec<AaAura>>,
+}
+
+impl RawShip {
+    fn parse(typeinfo: TypeInfo, entry: &RawValue) -> serde_json::Result<Self> {
+        let ship: Ship = serde_json::from_str(entry.get())?;
+        // The ship's fields are only parsed when one of its module configurations refers to them
+        let fields: HashMap<String, &RawValue> = serde_json::from_str(entry.get())?;
+        let components = |component: &str| -> Vec<Value> {
+            component_names(&ship.upgrade_info, component)
+                .iter()
+                .filter_map(|name| serde_json::from_str(fields.get(name)?.get()).ok())
+                .collect()
+        };
+        let hulls = components("hull")
+            .iter()
+            .filter_map(|hull| RawHull::deserialize(hull).ok())
+            .map(|hull| Hull {
+                health: hull.health,
+                max_speed: hull.max_speed,
+                surface_detection_km: hull.visibility_factor,
+                air_detection_km: hull.visibility_factor_by_plane,
+            })
+            .collect();
+        let main_battery = components("artillery")
+            .iter()
+            .map(RawArtillery::parse)
+            .collect();
+        let secondaries = components("atba").iter().map(RawArtillery::parse).collect();
+        let torpedoes = components("torpedoes").iter().map(group_guns).collect();
+        let air_defense = components("airDefense").iter().map(parse_auras).collect();
+
+        Ok(Self {
+            typeinfo,
+            id: ship.id,
+            name: ship.name,
+            level: ship.level,
+            abilities: ship.abilities,
+            hulls,
+            main_battery,
+            secondaries,
+            torpedoes,
+            air_defense,
+        })
+    }
 }
 
 impl ProcessedShip {
-    fn from(ship: Ship, abilities: &HashMap<&String, HashMap<&String, Ability>>) -> Self {
-        let abilities: Vec<_> = match ship.abilities {
+    fn from(
+        ship: RawShip,
+        abilities: &HashMap<String, HashMap<String, Ability>>,
+        projectiles: &HashMap<String, Projectile>,
+        modernizations: &[Modernization],
+    ) -> Self {
+        let mut abilities: Vec<_> = match &ship.abilities {
             Some(x) => x
-                .iter()
-                .map(|(_, v)| {
+                .values()
+                .map(|v| {
                     let options: Vec<_> = v
                         .abils
                         .iter()
                         .filter_map(|raw_abil| {
-                            // Note: Some abilities aren't actually defined, such as JP_Carriers_Gold
-                            let a = abilities.get(&raw_abil[0])?.get(&raw_abil[1])?.clone();
+                            // Note: Some abilities aren't actually defined, such as JP_Carriers_Gold,
+                            // and entries without a variant are skipped the same way
+                            let a = abilities
+                                .get(raw_abil.first()?)?
+                                .get(raw_abil.get(1)?)?
+                                .clone();
                             Some(a)
                         })
                         .collect();
-                    AbilitySlot { options }
+                    AbilitySlot {
+                        slot: v.slot,
+                        options,
+                    }
                 })
                 .collect(),
             None => vec![],
         };
+        abilities.sort_by_key(|x| x.slot);
+
+        let main_battery = ship
+            .main_battery
+            .into_iter()
+            .map(|x| resolve_artillery(x, projectiles))
+            .collect();
+        let secondaries = ship
+            .secondaries
+            .into_iter()
+            .map(|x| resolve_artillery(x, projectiles))
+            .collect();
+        let torpedoes = ship
+            .torpedoes
+            .into_iter()
+            .map(|x| resolve_torpedoes(x, projectiles))
+            .collect();
+
+        let nation = ship.typeinfo.nation.clone().unwrap_or_default();
+        let species = ship.typeinfo.species.clone().unwrap_or_default();
+        let (name, level) = (&ship.name, ship.level);
+        let mut upgrade_slots: Vec<UpgradeSlot> = vec![];
+        for modernization in modernizations
+            .iter()
+            .filter(|x| x.applies_to(name, level, &species, &nation))
+        {
+            let slot = modernization.slot as u32;
+            match upgrade_slots.iter_mut().find(|x| x.slot == slot) {
+                Some(x) => x.options.push(modernization.name.clone()),
+                None => upgrade_slots.push(UpgradeSlot {
+                    slot,
+                    options: vec![modernization.name.clone()],
+                }),
+            }
+        }
+        upgrade_slots.sort_by_key(|x| x.slot);
+        for slot in upgrade_slots.iter_mut() {
+            slot.options.sort();
+        }
+
         ProcessedShip {
             id: ship.id,
             name: ship.name,
             level: ship.level,
+            nation,
+            species,
             ability_slots: abilities,
+            hulls: ship.hulls,
+            main_battery,
+            secondaries,
+            torpedoes,
+            air_defense: ship.air_defense,
+            upgrade_slots,
         }
     }
+
+    pub fn max_health(&self) -> Option<f32> {
+        self.hulls.iter().map(|x| x.health).reduce(f32::max)
+    }
+
+    pub fn surface_detection(&self) -> Option<f32> {
+        self.hulls
+            .iter()
+            .map(|x| x.surface_detection_km)
+            .reduce(f32::min)
+    }
+
+    pub fn air_detection(&self) -> Option<f32> {
+        self.hulls
+            .iter()
+            .map(|x| x.air_detection_km)
+            .reduce(f32::min)
+    }
+
+    pub fn main_battery_range(&self) -> Option<f32> {
+        self.main_battery
+            .iter()
+            .map(|x| x.range_km)
+            .reduce(f32::max)
+    }
+
+    pub fn main_battery_caliber(&self) -> Option<f32> {
+        self.main_battery
+            .iter()
+            .flat_map(|x| x.guns.iter())
+            .map(|x| x.caliber_mm)
+            .reduce(f32::max)
+    }
 }
 
 pub struct GameParams {
@@ -94,75 +595,363 @@ struct TypeInfo {
     object_type: String,
 }
 
-impl GameParams {
-    pub fn load(data: &[u8]) -> Result<Self> {
-        let data = std::str::from_utf8(data).unwrap();
-        let v: Value = serde_json::from_str(data)?;
-        let v = v.as_object().unwrap();
-        println!("There are {} elements", v.len());
-        let mut type_counts: HashMap<String, usize> = HashMap::new();
-        for (_, entry) in v.iter() {
-            let typeinfo = entry
-                .as_object()
-                .unwrap()
-                .get("typeinfo")
-                .expect("Couldn't find typeinfo");
-            let typeinfo: TypeInfo = serde_json::value::from_value(typeinfo.clone())?;
-            if !type_counts.contains_key(&typeinfo.object_type) {
-                type_counts.insert(typeinfo.object_type.clone(), 0);
+/// Just enough of an entry to tell what it is
+#[derive(Deserialize)]
+struct EntryHeader {
+    typeinfo: TypeInfo,
+}
+
+/// Everything we keep from a single pass over GameParams. Ships are held onto until the end,
+/// since the abilities and projectiles they refer to may come after them in the file.
+#[derive(Default)]
+struct RawParams {
+    ships: Vec<RawShip>,
+    abilities: HashMap<String, HashMap<String, Ability>>,
+    projectiles: HashMap<String, Projectile>,
+    modernizations: Vec<Modernization>,
+    type_counts: HashMap<String, usize>,
+}
+
+impl RawParams {
+    fn add(&mut self, key: String, entry: &RawValue) -> serde_json::Result<()> {
+        let header: EntryHeader = serde_json::from_str(entry.get())?;
+        *self
+            .type_counts
+            .entry(header.typeinfo.object_type.clone())
+            .or_insert(0) += 1;
+        match header.typeinfo.object_type.as_str() {
+            "Ability" => {
+                // Each ability has a number of variants, alongside other non-ability fields
+                let variants: HashMap<String, Box<RawValue>> = serde_json::from_str(entry.get())?;
+                let ability = variants
+                    .into_iter()
+                    .filter_map(|(k, v)| Some((k, serde_json::from_str(v.get()).ok()?)))
+                    .collect();
+                self.abilities.insert(key, ability);
+            }
+            "Projectile" => {
+                // Not every projectile (e.g. bombs) has the fields we care about
+                if let Ok(projectile) = serde_json::from_str(entry.get()) {
+                    self.projectiles.insert(key, projectile);
+                }
+            }
+            "Modernization" => {
+                if let Ok(modernization) = serde_json::from_str(entry.get()) {
+                    self.modernizations.push(modernization);
+                }
+            }
+            "Ship" => {
+                self.ships.push(RawShip::parse(header.typeinfo, entry)?);
             }
-            *type_counts.get_mut(&typeinfo.object_type).unwrap() += 1;
+            _ => {}
         }
-        for (k, v) in type_counts.iter() {
-            println!("- {}: {}", k, v);
+        Ok(())
+    }
+}
+
+struct RawParamsVisitor;
+
+impl<'de> Visitor<'de> for RawParamsVisitor {
+    type Value = RawParams;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a map of GameParams entries")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<RawParams, A::Error> {
+        let mut params = RawParams::default();
+        while let Some(key) = map.next_key::<String>()? {
+            let entry: Box<RawValue> = map.next_value()?;
+            params
+                .add(key.clone(), &entry)
+                .map_err(|e| A::Error::custom(format!("malformed entry {}: {}", key, e)))?;
         }
+        Ok(params)
+    }
+}
 
-        // Parse out the abilities
-        let mut abilities = HashMap::new();
-        for (k, entry) in v.iter() {
-            let typeinfo = entry
-                .as_object()
-                .unwrap()
-                .get("typeinfo")
-                .expect("Couldn't find typeinfo");
-            let typeinfo: TypeInfo = serde_json::value::from_value(typeinfo.clone())?;
-            if typeinfo.object_type == "Ability" {
-                let entry = entry.as_object().unwrap();
-                let mut ability = HashMap::new();
-                for (k, v) in entry.iter() {
-                    match serde_json::value::from_value(v.clone()) {
-                        Ok(parsed_ability) => {
-                            ability.insert(k, parsed_ability);
-                        }
-                        Err(_) => {
-                            // Ignore
-                        }
-                    }
-                }
-                abilities.insert(k, ability);
+/// Decodes the game's own `GameParams.data` into the same JSON the usual converters produce: the
+/// file is reversed, zlib-compressed pickle data.
+pub fn decode_data<R: std::io::Read>(mut reader: R) -> Result<Value, Error> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    data.reverse();
+    let value = crate::pickle::unpickle(flate2::read::ZlibDecoder::new(&data[..]))?;
+
+    // Depending on the game version, the entries are wrapped in a tuple and/or a dict with a
+    // single empty key
+    let mut value = value;
+    loop {
+        value = match value {
+            Value::Array(mut items) if !items.is_empty() => items.swap_remove(0),
+            Value::Object(mut map) if map.len() == 1 && map.contains_key("") => {
+                map.remove("").unwrap_or_default()
+            }
+            value => return Ok(value),
+        };
+    }
+}
+
+impl GameParams {
+    /// Reads GameParams.json in a single pass, only keeping the entries we actually use.
+    pub fn load<R: std::io::Read>(reader: R) -> serde_json::Result<Self> {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        let raw = deserializer
+            .deserialize_map(RawParamsVisitor)
+            .and_then(|raw| deserializer.end().map(|_| raw))?;
+        Ok(Self::process(raw))
+    }
+
+    /// Reads the game's GameParams.data directly.
+    pub fn load_data<R: std::io::Read>(reader: R) -> Result<Self, Error> {
+        let entries = match decode_data(reader)? {
+            Value::Object(entries) => entries,
+            _ => {
+                return Err(Error::Unpickle {
+                    offset: 0,
+                    reason: "GameParams.data doesn't contain a map of entries".to_string(),
+                })
             }
+        };
+        let mut raw = RawParams::default();
+        for (key, entry) in entries {
+            serde_json::value::to_raw_value(&entry)
+                .and_then(|entry| raw.add(key.clone(), &entry))
+                .map_err(|err| Error::GameParams {
+                    err: serde_json::Error::custom(format!("malformed entry {}: {}", key, err)),
+                })?;
+        }
+        Ok(Self::process(raw))
+    }
+
+    fn process(raw: RawParams) -> Self {
+        debug!(
+            "GameParams has {} elements",
+            raw.type_counts.values().sum::<usize>()
+        );
+        for (k, v) in raw.type_counts.iter() {
+            debug!("- {}: {}", k, v);
         }
 
-        // Parse out the ships
         let mut ships = HashMap::new();
-        for (_, entry) in v.iter() {
-            let typeinfo = entry
-                .as_object()
-                .unwrap()
-                .get("typeinfo")
-                .expect("Couldn't find typeinfo");
-            let typeinfo: TypeInfo = serde_json::value::from_value(typeinfo.clone())?;
-            if typeinfo.object_type == "Ship" {
-                let ship: Ship = serde_json::value::from_value(entry.clone())?;
-                let ship = ProcessedShip::from(ship, &abilities);
-                ships.insert(ship.id, ship);
-            }
+        for ship in raw.ships {
+            let ship =
+                ProcessedShip::from(ship, &raw.abilities, &raw.projectiles, &raw.modernizations);
+            ships.insert(ship.id, ship);
         }
 
-        Ok(GameParams { ships })
+        GameParams { ships }
+    }
+
+    pub fn num_ships(&self) -> usize {
+        self.ships.len()
+    }
+
+    pub fn ship_ids(&self) -> impl Iterator<Item = &u64> {
+        self.ships.keys()
     }
 
     pub fn get_ship(&self, id: u64) -> Option<&ProcessedShip> {
         self.ships.get(&id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::GameParams;
+
+    #[test]
+    fn parses_ship_modules() {
+        let data = serde_json::json!({
+            "PAPA001_203mm_HE": {
+                "typeinfo": {"type": "Projectile", "species": "Artillery", "nation": "USA"},
+                "ammoType": "HE", "alphaDamage": 2800.0, "bulletSpeed": 762.0, "burnProb": 0.14,
+            },
+            "PAPT001_Torp": {
+                "typeinfo": {"type": "Projectile", "species": "Torpedo", "nation": "USA"},
+                "ammoType": "torpedo", "alphaDamage": 15000.0, "maxDist": 300.0, "speed": 60.0,
+                "visibilityFactor": 1.2,
+            },
+            "PCM001_MainGun_Mod_I": {
+                "typeinfo": {"type": "Modernization", "species": null, "nation": null},
+                "name": "PCM001_MainGun_Mod_I", "slot": 0, "shiplevel": [5, 6],
+                "shiptype": ["Cruiser"], "nation": [], "ships": [], "excludes": [],
+            },
+            "PCM002_Other_Mod": {
+                "typeinfo": {"type": "Modernization", "species": null, "nation": null},
+                "name": "PCM002_Other_Mod", "slot": 1, "shiplevel": [10],
+                "shiptype": [], "nation": [], "ships": [], "excludes": [],
+            },
+            "PASC001_Test_Cruiser": {
+                "typeinfo": {"type": "Ship", "species": "Cruiser", "nation": "USA"},
+                "id": 1234, "name": "PASC001_Test_Cruiser", "level": 5,
+                "ShipUpgradeInfo": {
+                    "costCR": 0,
+                    "PASU001_Hull": {
+                        "components": {
+                            "hull": ["A_Hull"], "artillery": ["A_Artillery"],
+                            "torpedoes": ["A_Torpedoes"], "airDefense": ["A_AirDefense"],
+                        },
+                    },
+                },
+                "A_Hull": {
+                    "health": 30000.0, "maxSpeed": 33.0, "visibilityFactor": 11.5,
+                    "visibilityFactorByPlane": 6.8,
+                },
+                "A_Artillery": {
+                    "maxDist": 15000.0,
+                    "HP_AGM_1": {
+                        "typeinfo": {"type": "Gun", "species": "Main", "nation": "USA"},
+                        "ammoList": ["PAPA001_203mm_HE"], "barrelDiameter": 0.203,
+                        "numBarrels": 3, "shotDelay": 15.0,
+                    },
+                    "HP_AGM_2": {
+                        "typeinfo": {"type": "Gun", "species": "Main", "nation": "USA"},
+                        "ammoList": ["PAPA001_203mm_HE"], "barrelDiameter": 0.203,
+                        "numBarrels": 3, "shotDelay": 15.0,
+                    },
+                },
+                "A_Torpedoes": {
+                    "HP_AGT_1": {
+                        "typeinfo": {"type": "Gun", "species": "Torpedo", "nation": "USA"},
+                        "ammoList": ["PAPT001_Torp"], "barrelDiameter": 0.533,
+                        "numBarrels": 4, "shotDelay": 90.0,
+                    },
+                },
+                "A_AirDefense": {
+                    "AuraFar": {
+                        "areaDamage": 70.0, "areaDamagePeriod": 0.285, "minDistance": 1000.0,
+                        "maxDistance": 5800.0,
+                    },
+                },
+            },
+        });
+        let data = serde_json::to_vec(&data).unwrap();
+        let params = GameParams::load(&data[..]).unwrap();
+        let ship = params.get_ship(1234).unwrap();
+
+        assert_eq!(ship.nation, "USA");
+        assert_eq!(ship.max_health(), Some(30000.0));
+        assert_eq!(ship.surface_detection(), Some(11.5));
+        assert_eq!(ship.air_detection(), Some(6.8));
+        assert_eq!(ship.main_battery_range(), Some(15.0));
+
+        let guns = &ship.main_battery[0].guns;
+        assert_eq!(guns.len(), 1);
+        assert_eq!(guns[0].mounts, 2);
+        assert_eq!(guns[0].barrels_per_mount, 3);
+        assert_eq!(guns[0].shells[0].ammo_type, "HE");
+        assert_eq!(guns[0].shells[0].alpha_damage, 2800.0);
+
+        let launchers = &ship.torpedoes[0][0];
+        assert_eq!(launchers.tubes_per_launcher, 4);
+        assert_eq!(launchers.torpedoes[0].speed_knots, 60.0);
+        assert!((launchers.torpedoes[0].range_km - 9.0).abs() < 0.01);
+
+        assert_eq!(ship.air_defense[0][0].max_range_km, 5.8);
+
+        assert_eq!(ship.upgrade_slots.len(), 1);
+        assert_eq!(ship.upgrade_slots[0].options, vec!["PCM001_MainGun_Mod_I"]);
+    }
+
+    #[test]
+    fn loads_gameparams_data() {
+        // A GPData ship with only a hull, pickled inside ({"": {"PASC001": ship}},) then
+        // compressed and reversed the way the game does it
+        let data = b"wq\xac\x1a\x03\xfa\xbf8\xdf\x10\x89\xb2\xc8\xe8\xde\xea\xdb3\x86CO\x91\xf3\xb7\xe8\xff;\xd0\xa3\x0b\xad\xb58%\x1an\xa7\xbf:y\xf7\x09\xf0\x5cfz\xb6\xf9\x14Z\xcblN)\xb1\x98\x9d?@D>\x86n\xfc\xe7\xdf\xad\xb68z\x9f\xa8\xc7\xb4\xe9\xea8\x95\xd7\x84!R\xe8\xe8\xa0\xd0\xda\x87\x15\xe0\xe2\xbc\xbd\xd3\xd7\xd8\x91\xd0*ad(\xe2\x0a7YT\xc4{\x12\xbb\x1f|\xc8{y\xd6\xacgX\xb3Hv%\xb6\x8e\xe6=\x94\xe8H\xfe8\xcd\xf9kIU=l|\xc6l\xf8\x8a\xaaL\xafM\x842\xa1Y\xe8\xa9\xf9~\xd1O\x98RR\x13eh\x06v2+)\x06\xc4(\xf2\x9aB\xc55'X%\x82\xe3$\xc3f\xa6\x813\x973\x11d\xf4\xd61\xe3\xc7CS\x00\xa5\x95#\xda\xb1!\xa1r\xf7P\x8f|<f<\xe9\xa5P\xd2=j%T/\xcb\x95\x13R\x9a;\xa3\xdaa\x03Bh\x8d\x80\xd3k\xb2\x8d\x7f\xbfa\x9c\x0d\x1a\x15\xd4\x89D\x8a\xc0\xa8b\x0c$\x94]\xd9\xeb\xbb;3X=\x91\xe9\xc7\x05\xfew \xfc\x0eT\xb0z*N&\xbc\xa5\x88jnB\x0b\xe4\xaa)T\x80@DR\x90?\x11P\x5c\xa2y\xe5\x02\xcf-\x0a\x13}\x0c\x100\xc3N\xcbPm\xdax";
+        let params = GameParams::load_data(&data[..]).unwrap();
+        let ship = params.get_ship(1234).unwrap();
+        assert_eq!(ship.nation, "USA");
+        assert_eq!(ship.max_health(), Some(30000.0));
+        assert_eq!(ship.surface_detection(), Some(11.5));
+    }
+
+    #[test]
+    fn reports_malformed_entries() {
+        let data = br#"{"PASC001_Test_Cruiser": {"typeinfo": {"type": "Ship"}, "id": "oops"}}"#;
+        match GameParams::load(&data[..]) {
+            Err(err) => assert!(err.to_string().contains("PASC001_Test_Cruiser"), "{}", err),
+            Ok(_) => panic!("Loaded a malformed ship"),
+        }
+
+        assert!(GameParams::load(&b"{\"truncated\": {"[..]).is_err());
+    }
+
+    #[test]
+    fn skips_abilities_without_a_variant() {
+        let data = serde_json::json!({
+            "PCY001_CrashCrew": {
+                "typeinfo": {"type": "Ability", "species": null, "nation": "Common"},
+                "Default": {
+                    "consumableType": "crashCrew", "group": "", "numConsumables": -1,
+                    "reloadTime": 60.0, "workTime": 5.0,
+                },
+            },
+            "PASC001_Test_Cruiser": {
+                "typeinfo": {"type": "Ship", "species": "Cruiser", "nation": "USA"},
+                "id": 1234, "name": "PASC001_Test_Cruiser", "level": 5,
+                "ShipAbilities": {
+                    "AbilitySlot0": {
+                        "abils": [["PCY001_CrashCrew", "Default"], ["PCY001_CrashCrew"]],
+                        "slot": 0,
+                    },
+                },
+            },
+        });
+        let data = serde_json::to_vec(&data).unwrap();
+        let params = GameParams::load(&data[..]).unwrap();
+        let ship = params.get_ship(1234).unwrap();
+        assert_eq!(ship.ability_slots.len(), 1);
+        assert_eq!(ship.ability_slots[0].options.len(), 1);
+        assert_eq!(
+            ship.ability_slots[0].options[0].consumable_type,
+            "crashCrew"
+        );
+    }
+
+    /// Peak resident memory of this process, in kB
+    fn peak_memory_kb() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    // Benchmarks against the real GameParams.json. Run them one at a time so that the peak memory
+    // numbers don't include the other benchmark, e.g.:
+    // cargo test --release bench_streaming_load -- --ignored --nocapture
+    //
+    // Against a 537 MB file with 600 ships (each with 60 components they don't use) and 20000
+    // projectiles:
+    //   bench_value_load:                          7.3s, peak 3652 MB
+    //   bench_streaming_load, keeping ship Values: 11.8s, peak 2740 MB
+    //   bench_streaming_load, typed ships:         6.8s, peak 16 MB
+
+    #[test]
+    #[ignore]
+    fn bench_streaming_load() {
+        let start = std::time::Instant::now();
+        let file = std::fs::File::open("GameParams.json").unwrap();
+        let params = GameParams::load(std::io::BufReader::new(file)).unwrap();
+        println!(
+            "Streaming load: {} ships in {:?}, peak memory {:?} kB",
+            params.ships.len(),
+            start.elapsed(),
+            peak_memory_kb()
+        );
+    }
+
+    /// How GameParams used to be loaded: the whole file, then the whole file as a Value
+    #[test]
+    #[ignore]
+    fn bench_value_load() {
+        let start = std::time::Instant::now();
+        let data = std::fs::read("GameParams.json").unwrap();
+        let params: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        println!(
+            "Value load: {} entries in {:?}, peak memory {:?} kB",
+            params.as_object().map(|x| x.len()).unwrap_or_default(),
+            start.elapsed(),
+            peak_memory_kb()
+        );
+    }
+}
@@ -1,131 +1,481 @@
+use std::collections::BTreeMap;
 use tracing::*;
 
+const NUM_BUCKETS: usize = 2_000;
+
+/// How many epochs a window is split into. Data ages out one epoch at a time.
+const EPOCHS_PER_WINDOW: i64 = 10;
+
 #[derive(Clone)]
 pub struct Histogram {
-    underlying: histogram::Histogram,
+    counts: Vec<u32>,
     bucket_size: f64,
-    num_buckets: u64,
+    total: u64,
+}
+
+fn bucket_size(max: f64) -> f64 {
+    // A zero, negative or non-finite range would leave us without any buckets
+    let max = if max.is_finite() && max > 0.0 {
+        max
+    } else {
+        1.0
+    };
+    max / NUM_BUCKETS as f64
+}
+
+fn bucket_of(value: f64, bucket_size: f64) -> usize {
+    // Negative values land in the bottom bucket, values past the max in the top one
+    ((value / bucket_size).floor().max(0.0) as usize).min(NUM_BUCKETS - 1)
 }
 
 impl Histogram {
     pub fn new(max: f64) -> Histogram {
-        let bucket_size = max / 10_000.0;
-        let num_buckets = (max / bucket_size) as u64;
         Histogram {
-            underlying: histogram::Histogram::configure()
-                .max_value(num_buckets)
-                .build()
-                .expect(&format!(
-                    "Could not construct histogram with {} buckets of size {}",
-                    num_buckets, bucket_size
-                )),
-            bucket_size: bucket_size,
-            num_buckets: num_buckets,
-        }
-    }
-
-    pub fn increment(&mut self, value: f32) -> Result<(), &'static str> {
-        // Ignore histogram errors to avoid "sample value too large" errors
-        let bucket = (value as f64 / self.bucket_size).floor() as u64;
-        if bucket >= self.num_buckets {
-            let _ = self.underlying.increment(self.num_buckets - 1);
-            return Ok(());
-        }
-        let _ = self.underlying.increment(bucket);
+            counts: vec![0; NUM_BUCKETS],
+            bucket_size: bucket_size(max),
+            total: 0,
+        }
+    }
+
+    fn bucket(&self, value: f64) -> usize {
+        bucket_of(value, self.bucket_size)
+    }
+
+    pub fn increment(&mut self, value: f64) -> Result<(), &'static str> {
+        if !value.is_finite() {
+            return Err("value is not finite");
+        }
+        let bucket = self.bucket(value);
+        self.counts[bucket] += 1;
+        self.total += 1;
         Ok(())
     }
 
-    pub fn percentile(&self, percentile: f64) -> Result<f64, &'static str> {
-        Ok(self.underlying.percentile(percentile)? as f64 * self.bucket_size)
+    pub fn decrement(&mut self, value: f64) -> Result<(), &'static str> {
+        if !value.is_finite() {
+            return Err("value is not finite");
+        }
+        let bucket = self.bucket(value);
+        if self.counts[bucket] == 0 {
+            return Err("value was never counted");
+        }
+        self.counts[bucket] -= 1;
+        self.total -= 1;
+        Ok(())
     }
 
-    pub fn get_percentile(&self, value: f64) -> Result<f64, &'static str> {
-        let mut lower = 0.0;
-        let mut upper = 100.0;
-        let mut mid = 50.0;
-        for _ in 0..10 {
-            let x = self.percentile(mid)?;
-            if x > value {
-                upper = mid;
-                mid = (upper + lower) / 2.;
-            } else {
-                lower = mid;
-                mid = (upper + lower) / 2.;
-            }
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    /// How many values are below the given one, counting values in the same bucket as half
+    #[cfg(test)]
+    pub fn count_below(&self, value: f64) -> f64 {
+        let bucket = self.bucket(value);
+        let below: u64 = self.counts[..bucket].iter().map(|x| *x as u64).sum();
+        below as f64 + self.counts[bucket] as f64 / 2.0
+    }
+}
+
+/// The time window a `RunningHistogram` covers, and how quickly old data within it fades out
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    pub length: chrono::Duration,
+    /// Contributions lose half their weight every this long
+    pub half_life: chrono::Duration,
+}
+
+impl Window {
+    pub fn days(days: i64) -> Self {
+        Self {
+            length: chrono::Duration::days(days),
+            half_life: chrono::Duration::days(days) / 2,
         }
-        Ok(mid)
+    }
+
+    fn epoch_secs(&self) -> i64 {
+        (self.length.num_seconds() / EPOCHS_PER_WINDOW).max(1)
+    }
+
+    pub fn epoch_of(&self, at: chrono::DateTime<chrono::Utc>) -> i64 {
+        at.timestamp().div_euclid(self.epoch_secs())
     }
 }
 
-/// Maintains a set of N histograms (N=10). Queries are returned based on the oldest histogram.
-/// All histograms are updated simultaneously. After a certain number of items are processed,
-/// the oldest histogram is removed and a new histogram is added.
+/// Histogram of values over a sliding time window.
 ///
-/// The number of items between refreshes is total_items / N. total_items is, of course, not
-/// known in advance - therefore, it is periodically updated, according to the number of
-/// elements in the database.
+/// Values are added along with the time they were retrieved, and kept in one histogram per epoch
+/// (a tenth of the window). Queries weigh each epoch by its age, so older data fades out
+/// gradually, and whole epochs are dropped once they fall out of the window.
+///
+/// Because values are filed by when they were retrieved rather than when they were added, a value
+/// that is replaced (say, a player who gets re-scraped) can be taken back out again with
+/// `decrement`, so it isn't counted twice.
 pub struct RunningHistogram {
     label: String,
-    histograms: Vec<Histogram>,
+    window: Window,
+    epochs: BTreeMap<i64, Histogram>,
     pub max_value: f64,
-    database_size: u64,
-    items_processed: u64,
 }
 
 impl RunningHistogram {
-    pub fn new(label: String, max_value: f64) -> Self {
+    pub fn new(label: String, max_value: f64, window: Window) -> Self {
         Self {
             label,
-            histograms: vec![],
-            max_value: max_value,
-            database_size: 1_000,
-            items_processed: 0,
+            window,
+            epochs: BTreeMap::new(),
+            max_value: if max_value.is_finite() {
+                max_value
+            } else {
+                0.0
+            },
         }
     }
 
-    pub fn increment(&mut self, value: f64) {
-        self.items_processed += 1;
+    pub fn increment(&mut self, value: f64, retrieved: chrono::DateTime<chrono::Utc>) {
+        let epoch = self.window.epoch_of(retrieved);
+        self.add(value, epoch);
+        self.expire(epoch);
+    }
+
+    /// Adds a batch of values, only checking for expired epochs once at the end
+    pub fn increment_all(&mut self, values: &[(f64, chrono::DateTime<chrono::Utc>)]) {
+        let mut latest = None;
+        for (value, retrieved) in values {
+            let epoch = self.window.epoch_of(*retrieved);
+            self.add(*value, epoch);
+            latest = latest.max(Some(epoch));
+        }
+        if let Some(epoch) = latest {
+            self.expire(epoch);
+        }
+    }
+
+    fn add(&mut self, value: f64, epoch: i64) {
+        // NaN would otherwise land in the bottom bucket and infinity would blow up max_value
+        if !value.is_finite() {
+            trace!("Ignoring {} for histogram {}", value, self.label);
+            return;
+        }
         if value > self.max_value {
             self.max_value = value;
         }
 
-        let need_refresh =
-            self.items_processed > self.database_size / 10 || self.histograms.len() == 0;
-
-        if need_refresh {
-            if self.histograms.len() > 10 {
-                self.histograms.remove(0);
-            }
+        if !self.epochs.contains_key(&epoch) {
             trace!(
-                "Refreshing histogram {} with max_value {}",
+                "Starting epoch {} of histogram {} with max_value {}",
+                epoch,
                 self.label,
                 self.max_value
             );
-            self.histograms.push(Histogram::new(self.max_value));
-            self.items_processed = 0;
         }
+        let max_value = self.max_value;
+        let histogram = self
+            .epochs
+            .entry(epoch)
+            .or_insert_with(|| Histogram::new(max_value));
+        if let Err(e) = histogram.increment(value) {
+            error!(
+                "Error {:?} incrementing histogram {} with {}",
+                e, self.label, value
+            );
+        }
+    }
 
-        for histogram in self.histograms.iter_mut() {
-            // TODO: Make the underlying accept f64
-            if let Err(e) = histogram.increment(value as f32) {
-                error!(
-                    "Error {:?} incrementing histogram {} with {}",
+    /// Takes back a value previously added with the same retrieval time
+    pub fn decrement(&mut self, value: f64, retrieved: chrono::DateTime<chrono::Utc>) {
+        if !value.is_finite() {
+            return;
+        }
+        let epoch = self.window.epoch_of(retrieved);
+        // If the epoch has already expired, there's nothing left to take back
+        if let Some(histogram) = self.epochs.get_mut(&epoch) {
+            if let Err(e) = histogram.decrement(value) {
+                debug!(
+                    "Error {:?} decrementing histogram {} with {}",
                     e, self.label, value
                 );
             }
         }
     }
 
-    pub fn percentile(&self, percentile: f64) -> Result<f64, &'static str> {
-        if self.histograms.len() == 0 {
+    /// Drops the epochs which are entirely outside the window ending at the given epoch
+    fn expire(&mut self, latest_epoch: i64) {
+        let newest = self.epochs.keys().next_back().copied().unwrap_or(0);
+        let oldest_kept = latest_epoch.max(newest) - EPOCHS_PER_WINDOW + 1;
+        self.epochs = self.epochs.split_off(&oldest_kept);
+    }
+
+    fn weighted_epochs(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> impl Iterator<Item = (f64, &Histogram)> {
+        let current = self.window.epoch_of(now);
+        let epoch_secs = self.window.epoch_secs() as f64;
+        let half_life = self.window.half_life.num_seconds().max(1) as f64;
+        self.epochs
+            .range(current - EPOCHS_PER_WINDOW + 1..=current)
+            .map(move |(epoch, histogram)| {
+                let age = (current - epoch) as f64 * epoch_secs;
+                (0.5f64.powf(age / half_life), histogram)
+            })
+    }
+
+    /// How many values are currently within the window, regardless of their weight
+    pub fn len(&self, now: chrono::DateTime<chrono::Utc>) -> u64 {
+        self.weighted_epochs(now).map(|(_, h)| h.len()).sum()
+    }
+
+    /// The percentage of values within the window that are below the given one. Queries go
+    /// through a `Snapshot` outside of tests; this is what snapshots are checked against.
+    #[cfg(test)]
+    pub fn percentile(
+        &self,
+        value: f64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<f64, &'static str> {
+        if !value.is_finite() {
+            return Err("value is not finite");
+        }
+        let mut below = 0.0;
+        let mut total = 0.0;
+        for (weight, histogram) in self.weighted_epochs(now) {
+            below += weight * histogram.count_below(value);
+            total += weight * histogram.len() as f64;
+        }
+        if total <= 0.0 {
+            return Ok(0.0);
+        }
+        Ok((100.0 * below / total).clamp(0.0, 100.0))
+    }
+
+    /// Freezes the histogram as of the given time, for answering queries without it
+    pub fn snapshot(&self, now: chrono::DateTime<chrono::Utc>) -> Snapshot {
+        let bucket_size = bucket_size(self.max_value);
+        let mut weighted = vec![0.0; NUM_BUCKETS];
+        for (weight, histogram) in self.weighted_epochs(now) {
+            // Epochs started before max_value last grew have finer buckets, which fold into ours
+            for (i, count) in histogram.counts.iter().enumerate() {
+                if *count > 0 {
+                    let center = (i as f64 + 0.5) * histogram.bucket_size;
+                    weighted[bucket_of(center, bucket_size)] += weight * *count as f64;
+                }
+            }
+        }
+        let mut total = 0.0;
+        let cumulative = weighted
+            .into_iter()
+            .map(|x| {
+                total += x;
+                total
+            })
+            .collect();
+        Snapshot {
+            bucket_size,
+            cumulative,
+            len: self.len(now),
+        }
+    }
+}
+
+/// A `RunningHistogram` frozen at some point in time, with the epochs already weighed and folded
+/// into one set of buckets. It never changes once built, so it can be handed to any number of
+/// readers without locking, and a percentile is just two lookups.
+pub struct Snapshot {
+    bucket_size: f64,
+    /// The weighted count of values in each bucket and every bucket below it
+    cumulative: Vec<f64>,
+    len: u64,
+}
+
+impl Snapshot {
+    /// How many values were within the window, regardless of their weight
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// The percentage of values that are below the given one
+    pub fn percentile(&self, value: f64) -> Result<f64, &'static str> {
+        if !value.is_finite() {
+            return Err("value is not finite");
+        }
+        let total = self.cumulative.last().copied().unwrap_or(0.0);
+        if total <= 0.0 {
             return Ok(0.0);
         }
-        self.histograms[0].get_percentile(percentile)
+        let bucket = bucket_of(value, self.bucket_size);
+        let below = if bucket == 0 {
+            0.0
+        } else {
+            self.cumulative[bucket - 1]
+        };
+        let within = self.cumulative[bucket] - below;
+        Ok((100.0 * (below + within / 2.0) / total).clamp(0.0, 100.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use rand::{Rng, SeedableRng};
+
+    fn random_value(rng: &mut rand::rngs::StdRng) -> f64 {
+        match rng.gen_range(0..20) {
+            0 => f64::NAN,
+            1 => f64::INFINITY,
+            2 => f64::NEG_INFINITY,
+            3 => -rng.gen_range(0.0..1000.0),
+            4 => rng.gen_range(1e6..1e12),
+            _ => rng.gen_range(0.0..1000.0),
+        }
+    }
+
+    fn start() -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc.ymd(2021, 6, 1).and_hms(0, 0, 0)
+    }
+
+    #[test]
+    fn percentiles_stay_sane_for_any_input() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for round in 0..50 {
+            let initial_max = match round % 4 {
+                0 => 0.0,
+                1 => f64::NAN,
+                _ => rng.gen_range(1.0..10_000.0),
+            };
+            let mut h =
+                RunningHistogram::new(format!("test-{}", round), initial_max, Window::days(30));
+            let mut now = start();
+            for _ in 0..rng.gen_range(1..1_000) {
+                now = now + chrono::Duration::minutes(rng.gen_range(0..600));
+                h.increment(random_value(&mut rng), now);
+            }
+            assert!(h.max_value.is_finite());
+
+            let mut queries: Vec<f64> = (0..50).map(|_| rng.gen_range(-10.0..1e7)).collect();
+            queries.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let mut last = 0.0;
+            for q in queries {
+                let p = h.percentile(q, now).unwrap();
+                assert!((0.0..=100.0).contains(&p), "percentile {} of {}", p, q);
+                assert!(
+                    p >= last,
+                    "percentile went down from {} to {} at {}",
+                    last,
+                    p,
+                    q
+                );
+                last = p;
+            }
+
+            assert!(h.percentile(f64::NAN, now).is_err());
+            assert!(h.percentile(f64::INFINITY, now).is_err());
+        }
+    }
+
+    #[test]
+    fn non_finite_values_are_ignored() {
+        let mut h = RunningHistogram::new("test".to_string(), 100.0, Window::days(30));
+        for i in 0..100 {
+            h.increment(i as f64, start());
+            h.increment(f64::NAN, start());
+            h.increment(f64::INFINITY, start());
+        }
+        assert_eq!(h.max_value, 100.0);
+        assert_eq!(h.len(start()), 100);
+        // If NaNs had counted as zeros, 50 would be around the 66th percentile
+        let p = h.percentile(50.0, start()).unwrap();
+        assert!((45.0..55.0).contains(&p), "got {}", p);
+    }
+
+    #[test]
+    fn percentiles_are_stable_across_rotations() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut h = RunningHistogram::new("test".to_string(), 1000.0, Window::days(30));
+        let mut now = start();
+        // A steady stream of values over three windows, checking in every few hours
+        for step in 0..(90 * 24) {
+            for _ in 0..50 {
+                h.increment(rng.gen_range(0.0..1000.0), now);
+            }
+            now = now + chrono::Duration::hours(1);
+            if step > 48 && step % 5 == 0 {
+                let p = h.percentile(500.0, now).unwrap();
+                assert!((47.0..53.0).contains(&p), "got {} at step {}", p, step);
+            }
+        }
+        // Nothing older than the window is kept around
+        assert!(h.epochs.len() as i64 <= EPOCHS_PER_WINDOW);
+    }
+
+    #[test]
+    fn old_data_fades_out() {
+        let mut h = RunningHistogram::new("test".to_string(), 1000.0, Window::days(30));
+        for _ in 0..1000 {
+            h.increment(100.0, start());
+        }
+        let later = start() + chrono::Duration::days(20);
+        for _ in 0..1000 {
+            h.increment(900.0, later);
+        }
+        // Both are in the window, but the recent values count for more
+        let p = h.percentile(500.0, later).unwrap();
+        assert!(p > 15.0 && p < 40.0, "got {}", p);
+        // A window later, only the recent values are left
+        let much_later = start() + chrono::Duration::days(40);
+        assert_eq!(h.percentile(500.0, much_later).unwrap(), 0.0);
+        assert_eq!(h.len(much_later), 1000);
+    }
+
+    #[test]
+    fn snapshots_match_the_live_histogram() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        let mut h = RunningHistogram::new("test".to_string(), 10.0, Window::days(10));
+        let mut now = start();
+        // The range grows every epoch, so every epoch has different buckets
+        for day in 0..15 {
+            let max = 100.0 * (day + 1) as f64;
+            let mut values = vec![(max, now)];
+            values.extend((0..200).map(|_| (rng.gen_range(0.0..max), now)));
+            h.increment_all(&values);
+            now = now + chrono::Duration::days(1);
+        }
+        let snapshot = h.snapshot(now);
+        assert_eq!(snapshot.len(), h.len(now));
+        for q in (0..32).map(|x| x as f64 * 50.0) {
+            let live = h.percentile(q, now).unwrap();
+            let frozen = snapshot.percentile(q).unwrap();
+            assert!(
+                (live - frozen).abs() < 0.5,
+                "{} vs {} at {}",
+                live,
+                frozen,
+                q
+            );
+        }
+        assert!(snapshot.percentile(f64::NAN).is_err());
+        assert_eq!(
+            RunningHistogram::new("empty".to_string(), 1.0, Window::days(30))
+                .snapshot(now)
+                .percentile(1.0)
+                .unwrap(),
+            0.0
+        );
     }
 
-    pub fn update_db_size(&mut self, db_size: u64) {
-        if db_size > 1_000 {
-            self.database_size = db_size;
+    #[test]
+    fn decrementing_undoes_increments() {
+        let mut h = RunningHistogram::new("test".to_string(), 1000.0, Window::days(30));
+        for i in 0..10 {
+            h.increment(i as f64 * 100.0, start());
         }
+        // The player with 900 gets re-scraped, and now has 50
+        let later = start() + chrono::Duration::days(1);
+        h.decrement(900.0, start());
+        h.increment(50.0, later);
+        assert_eq!(h.len(later), 10);
+        let p = h.percentile(850.0, later).unwrap();
+        assert!(p > 99.0, "got {}", p);
     }
 }
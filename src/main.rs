@@ -2,31 +2,39 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 use futures::TryStreamExt;
 use mongodb::bson::doc;
+use rocket::http::Status;
 use rocket::State;
 use rocket::{get, routes};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use tera::{Context, Tera};
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use std::sync::Arc;
+use tera::Context;
 use tracing::*;
 use tracing_subscriber::prelude::*;
 
+mod analysis;
 mod cheatsheet;
 mod database;
 mod error;
 mod gameparams;
 mod histogram;
+mod metrics;
+mod pickle;
 mod progress_logger;
+mod rate_limiter;
+mod realm_stats;
 mod scraper;
+mod ship_changes;
 mod ships;
 mod statistics;
+mod summary;
+mod templates;
 mod wows_data;
 
-use crate::cheatsheet::CheatsheetDb;
+use crate::cheatsheet::{CheatsheetDb, CheatsheetExport, CheatsheetQuery, ExportFormat};
 use crate::database::*;
-use crate::gameparams::GameParams;
+use crate::metrics::MetricRegistry;
 use crate::statistics::*;
+use crate::templates::Templates;
 use error::Error;
 use wows_data::*;
 
@@ -35,73 +43,91 @@ fn index() -> &'static str {
     "Hello there! Go ahead and go to the URL /warshipstats/player/<your username> to see your stats."
 }
 
-#[get("/cheatsheet/<tier>")]
+#[get("/cheatsheet/<tier>?<query..>")]
 fn render_cheatsheet(
     tier: u16,
+    query: CheatsheetQuery,
     database: &State<CheatsheetDb>,
-) -> rocket::response::content::Html<String> {
-    let mut tera = Tera::new("templates/*").unwrap();
-    tera.add_raw_template(
-        "cheatsheet.html",
-        std::include_str!("../templates/cheatsheet.html"),
-    )
-    .unwrap();
-    tera.register_filter(
-        "shortclass",
-        |class: &tera::Value, _: &HashMap<String, tera::Value>| {
-            let class: crate::cheatsheet::ShipClass =
-                serde_json::value::from_value(class.clone()).unwrap();
-            Ok(serde_json::value::to_value(class.short()).unwrap())
-        },
-    );
-    tera.register_tester("none", |value: Option<&tera::Value>, _: &[tera::Value]| {
-        Ok(value.unwrap().is_null())
-    });
-    tera.register_filter(
-        "unwrap_float",
-        |value: &tera::Value, _: &HashMap<String, tera::Value>| {
-            let value: Option<f32> = serde_json::value::from_value(value.clone()).unwrap();
-            let value = value.unwrap();
-            Ok(serde_json::value::to_value(value).unwrap())
-        },
-    );
-
-    let mut ships = vec![];
-    for id in database.enumerate_ships().iter() {
-        let ship = database.get_ship(*id).unwrap();
-        if ship.min_tier <= tier && ship.max_tier >= tier {
-            ships.push(ship);
-        }
-    }
-    ships.sort_by_key(|ship| (ship.class, ship.name.clone()));
-    let ships: Vec<tera::Value> = ships
+    templates: &State<Templates>,
+) -> Result<rocket::response::content::Html<String>, Status> {
+    let query = query.normalized();
+    let ships: Vec<tera::Value> = database
+        .ships_for(tier, &query)
         .iter()
-        .map(|x| serde_json::value::to_value(x).unwrap())
-        .collect();
+        .map(serde_json::value::to_value)
+        .collect::<Result<_, _>>()
+        .map_err(|e| {
+            error!("Couldn't serialize cheatsheet ships: {:?}", e);
+            Status::InternalServerError
+        })?;
 
     let mut context: HashMap<&'static str, tera::Value> = HashMap::new();
     context.insert("tier", tier.into());
     context.insert("ships", ships.into());
-    rocket::response::content::Html(
-        tera.render(
-            "cheatsheet.html",
-            &Context::from_serialize(&context).unwrap(),
-        )
-        .unwrap(),
-    )
+    context.insert("query", query.to_query_string().into());
+    context.insert(
+        "filter",
+        serde_json::json!({
+            "class": query.class,
+            "nation": query.nation,
+            "premium": query.premium,
+            "sort": query.sort,
+        }),
+    );
+    let context = Context::from_serialize(&context).map_err(|_| Status::InternalServerError)?;
+    templates
+        .render("cheatsheet.html", &context)
+        .map(rocket::response::content::Html)
+}
+
+#[get("/cheatsheet/mismatches")]
+fn cheatsheet_mismatches(
+    database: &State<CheatsheetDb>,
+) -> rocket::response::content::Json<String> {
+    rocket::response::content::Json(serde_json::to_string(&database.mismatches()).unwrap())
+}
+
+#[get("/cheatsheet/<export>?<query..>", rank = 2)]
+fn export_cheatsheet(
+    export: CheatsheetExport,
+    query: CheatsheetQuery,
+    database: &State<CheatsheetDb>,
+) -> Result<(rocket::http::ContentType, String), Status> {
+    let ships = database.ships_for(export.tier, &query.normalized());
+    match export.format {
+        ExportFormat::Json => Ok((
+            rocket::http::ContentType::JSON,
+            serde_json::to_string(&ships).map_err(|e| {
+                error!("Couldn't export cheatsheet as JSON: {:?}", e);
+                Status::InternalServerError
+            })?,
+        )),
+        ExportFormat::Csv => Ok((
+            rocket::http::ContentType::CSV,
+            crate::cheatsheet::to_csv(&ships).map_err(|e| {
+                error!("Couldn't export cheatsheet as CSV: {:?}", e);
+                Status::InternalServerError
+            })?,
+        )),
+    }
 }
 
 async fn build_playerstats_context(
     username: &str,
     database: &mongodb::Database,
-    histograms: &Arc<Mutex<StatsHistogram>>,
+    histograms: &Arc<StatsHistogram>,
     shipdb: &crate::ships::ShipDb,
+    client: &crate::scraper::WowsClient,
 ) -> HashMap<String, tera::Value> {
-    // Get the player's ID
+    // Get the player's ID, asking the API if the poller hasn't gotten to them yet
     let username = username.to_lowercase();
     let collection = database.collection::<PlayerRecord>("playerids");
     let filter = doc! { "nickname": username.clone() };
     let record = match collection.find_one(filter, None).await.unwrap() {
+        Some(x) => Some(x),
+        None => database::lookup_player(client, database, histograms, &username).await,
+    };
+    let record = match record {
         Some(x) => x,
         None => {
             error!("Could not find username '{}'", username);
@@ -123,6 +149,7 @@ async fn build_playerstats_context(
     context.insert("error".to_owned(), (false).into());
 
     let mut ships: Vec<tera::Value> = vec![];
+    let mut summary_entries = vec![];
     while let Some(ship_stats) = cursor.try_next().await.unwrap() {
         let ship_id = ship_stats.ship_id;
 
@@ -135,12 +162,13 @@ async fn build_playerstats_context(
         // Collect some meta information about the ship itself
         let mut ship: tera::Map<String, tera::Value> = tera::Map::new();
 
-        if let Some(ship_info) = shipdb.get_ship_info(ship_id) {
+        let ship_info = shipdb.get_ship_info(ship_id);
+        if let Some(ship_info) = &ship_info {
             ship.insert("known".to_owned(), (true).into());
             ship.insert("tier".to_owned(), ship_info.tier.into());
-            ship.insert("nation".to_owned(), ship_info.nation.into());
-            ship.insert("ship_type".to_owned(), ship_info.ship_type.into());
-            ship.insert("name".to_owned(), ship_info.name.into());
+            ship.insert("nation".to_owned(), ship_info.nation.clone().into());
+            ship.insert("ship_type".to_owned(), ship_info.ship_type.clone().into());
+            ship.insert("name".to_owned(), ship_info.name.clone().into());
         } else {
             ship.insert("known".to_owned(), (false).into());
         }
@@ -149,44 +177,126 @@ async fn build_playerstats_context(
         ship.insert("shipid".to_owned(), ship_id.into());
 
         // Collect the statistics about the player's performance on the ship
-        let histograms = histograms.lock().unwrap();
         let percentiles = histograms.get_percentiles(ship_id, &ship_stats.pvp);
+        ship.insert("cohort".to_owned(), percentiles.cohort.describe().into());
 
-        let percentiles: tera::Map<String, tera::Value> = percentiles
+        let percentile_values: tera::Map<String, tera::Value> = percentiles
+            .values
             .iter()
             .map(|(k, v)| (k.to_owned(), (*v).into()))
             .collect();
-        ship.insert("percentiles".to_owned(), percentiles.into());
-        let ship_stats: tera::Map<String, tera::Value> = ship_stats
-            .pvp
-            .into_map()
+        ship.insert("percentiles".to_owned(), percentile_values.into());
+        let stats: tera::Map<String, tera::Value> = histograms
+            .metrics()
+            .extract(&ship_stats.pvp)
             .iter()
             .map(|(k, v)| (k.to_owned(), (*v).into()))
             .collect();
-        ship.insert("stats".to_owned(), ship_stats.into());
+        ship.insert("stats".to_owned(), stats.into());
+
+        // How far off the stats could be, given how many battles they're from
+        let intervals = histograms.metrics().intervals(&ship_stats.pvp);
+        let reliable = crate::metrics::is_reliable(&intervals);
+        ship.insert("reliable".to_owned(), reliable.into());
+        ship.insert(
+            "intervals".to_owned(),
+            serde_json::to_value(&intervals).unwrap_or_default(),
+        );
 
         ships.push(ship.into());
+        summary_entries.push((ship_stats, ship_info, percentiles.values, reliable));
     }
 
+    // Totals across the whole account
+    let summary_entries: Vec<crate::summary::ShipEntry> = summary_entries
+        .iter()
+        .map(
+            |(ship_stats, info, percentiles, reliable)| crate::summary::ShipEntry {
+                ship_id: ship_stats.ship_id,
+                info: info.as_ref(),
+                stats: &ship_stats.pvp,
+                percentiles,
+                reliable: *reliable,
+            },
+        )
+        .collect();
+    let summary = crate::summary::AccountSummary::new(&summary_entries);
+    context.insert(
+        "summary".to_owned(),
+        serde_json::to_value(&summary).unwrap_or_default(),
+    );
+    let analysis = crate::analysis::Analysis::new(&summary_entries);
+    context.insert(
+        "analysis".to_owned(),
+        serde_json::to_value(&analysis).unwrap_or_default(),
+    );
+
     context.insert("ships".to_owned(), ships.into());
     context.insert("username".to_owned(), username.into());
     context
 }
 
+/// Serializes a JSON response, answering with a 500 if that fails
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String, Status> {
+    serde_json::to_string(value).map_err(|e| {
+        error!("Couldn't serialize response: {:?}", e);
+        Status::InternalServerError
+    })
+}
+
 #[get("/ships")]
-async fn ship_data(shipdb: &State<crate::ships::ShipDb>) -> String {
+async fn ship_data(shipdb: &State<crate::ships::ShipDb>) -> Result<String, Status> {
     let ships = shipdb.get_all_info();
-    serde_json::to_string(&ships).unwrap()
+    to_json(&ships)
+}
+
+#[get("/ships/<id>/params")]
+fn ship_params(id: u64, database: &State<CheatsheetDb>) -> Result<Option<String>, Status> {
+    match database.get_params(id) {
+        Some(params) => to_json(&params).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[get("/ships/changes")]
+async fn ship_change_log(database: &State<mongodb::Database>) -> Result<String, Status> {
+    let changes = crate::ship_changes::load_changes(database).await;
+    to_json(&changes)
+}
+
+#[get("/stats")]
+fn realm_stats_page(
+    stats: &State<crate::realm_stats::RealmStatsDb>,
+    templates: &State<Templates>,
+) -> Result<String, Status> {
+    let mut context = Context::new();
+    context.insert("stats", &stats.get());
+    context.insert(
+        "min_balance_players",
+        &crate::realm_stats::MIN_BALANCE_PLAYERS,
+    );
+    templates.render("realmstats.txt", &context)
+}
+
+#[get("/stats-raw")]
+fn realm_stats_raw(stats: &State<crate::realm_stats::RealmStatsDb>) -> Result<String, Status> {
+    to_json(&stats.get())
+}
+
+#[get("/api-keys")]
+fn api_key_usage(client: &State<crate::scraper::WowsClient>) -> Result<String, Status> {
+    to_json(&client.key_usage())
 }
 
 #[get("/player-raw/<username>")]
 async fn player_stats_raw(
     username: &str,
     database: &State<mongodb::Database>,
-    histograms: &State<Arc<Mutex<StatsHistogram>>>,
+    histograms: &State<Arc<StatsHistogram>>,
     ships: &State<crate::ships::ShipDb>,
+    client: &State<crate::scraper::WowsClient>,
 ) -> String {
-    let context = build_playerstats_context(username, database, histograms, ships).await;
+    let context = build_playerstats_context(username, database, histograms, ships, client).await;
 
     serde_json::to_string(&context).unwrap()
 }
@@ -195,96 +305,138 @@ async fn player_stats_raw(
 async fn player_stats(
     username: &str,
     database: &State<mongodb::Database>,
-    histograms: &State<Arc<Mutex<StatsHistogram>>>,
+    histograms: &State<Arc<StatsHistogram>>,
     ships: &State<crate::ships::ShipDb>,
-) -> String {
-    let context = build_playerstats_context(username, database, histograms, ships).await;
-
-    let mut tera = Tera::new("templates/*").unwrap();
-    tera.add_raw_template(
-        "playerstats.txt",
-        &std::fs::read_to_string("./templates/playerstats.txt")
-            .unwrap_or(std::include_str!("../templates/playerstats.txt").to_string()),
-    )
-    .unwrap();
-
-    tera.register_tester("none", |value: Option<&tera::Value>, _: &[tera::Value]| {
-        Ok(value.unwrap().is_null())
-    });
-    tera.register_filter(
-        "unwrap_float",
-        |value: &tera::Value, _: &HashMap<String, tera::Value>| {
-            let value: Option<f32> = serde_json::value::from_value(value.clone()).unwrap();
-            let value = value.unwrap_or(0.0);
-            Ok(serde_json::value::to_value(value).unwrap())
-        },
-    );
-    tera.register_filter(
-        "mult100",
-        |value: &tera::Value, _: &HashMap<String, tera::Value>| {
-            let value: f32 = serde_json::value::from_value(value.clone()).unwrap();
-            Ok(serde_json::value::to_value(value * 100.0).unwrap())
-        },
-    );
-
-    tera.render(
-        "playerstats.txt",
-        &Context::from_serialize(&context).unwrap(),
-    )
-    .unwrap()
+    client: &State<crate::scraper::WowsClient>,
+    templates: &State<Templates>,
+) -> Result<String, Status> {
+    let context = build_playerstats_context(username, database, histograms, ships, client).await;
+    let context = Context::from_serialize(&context).map_err(|_| Status::InternalServerError)?;
+    templates.render("playerstats.txt", &context)
 }
 
 struct Config {
     disable_scraper: bool,
-    api_key: String,
-    request_period: u64,
+    api_keys: Vec<String>,
+    rate_limits: crate::scraper::RateLimits,
     mongo_url: String,
+    ship_cache_path: String,
+    gameparams_path: String,
+    template_reload: bool,
+    metrics_path: Option<String>,
+    histogram_window_days: i64,
+}
+
+/// Reads a request rate, which has to be positive or the rate limiter could never let a request
+/// through. Rates without a default are required.
+fn parse_rate(
+    settings: &HashMap<String, String>,
+    key: &str,
+    default: Option<f64>,
+) -> Result<f64, Error> {
+    let invalid = |reason: &str| Error::Settings {
+        key: key.to_string(),
+        err: config::ConfigError::Message(reason.to_string()),
+    };
+    let rate: f64 = match (settings.get(key), default) {
+        (Some(x), _) => x.parse().map_err(|_| invalid("not a number"))?,
+        (None, Some(x)) => x,
+        (None, None) => panic!("Could not find '{}' in settings", key),
+    };
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(invalid("must be greater than 0"));
+    }
+    Ok(rate)
+}
+
+/// Lists (such as multiple API keys) are flattened into comma-separated strings
+fn flatten_settings(
+    settings: HashMap<String, config::Value>,
+) -> Result<HashMap<String, String>, Error> {
+    settings
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value.clone().into_array() {
+                Ok(values) => values
+                    .into_iter()
+                    .map(|v| v.into_str())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|values| values.join(",")),
+                Err(_) => value.into_str(),
+            };
+            match value {
+                Ok(value) => Ok((key, value)),
+                Err(err) => Err(Error::Settings { key, err }),
+            }
+        })
+        .collect()
 }
 
 impl Config {
-    fn from_map(settings: HashMap<String, String>) -> Config {
+    fn from_map(settings: HashMap<String, String>) -> Result<Config, Error> {
         let disable_scraper = match settings.get("disable_scraper") {
             Some(x) => x.parse().unwrap(),
             None => false,
         };
-        let api_key = settings
+        // Multiple keys can be given as a list, or as a comma-separated string
+        let api_keys: Vec<String> = settings
             .get("api_key")
             .expect("Could not find 'api_key' in settings")
-            .to_string();
-        let request_rate: f64 = settings
-            .get("api_request_rate")
-            .expect("Could not find 'api_request_rate' in settings")
-            .parse()
-            .expect("Could not parse api_request_rate as a float");
+            .split(',')
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect();
+        let rate_limits = crate::scraper::RateLimits {
+            floor: parse_rate(&settings, "api_request_rate_floor", Some(1.0))?,
+            background_ceiling: parse_rate(&settings, "api_request_rate", None)?,
+            interactive_ceiling: parse_rate(&settings, "api_interactive_request_rate", Some(2.0))?,
+        };
         let mongo_url = settings
             .get("mongo")
             .expect("Could not find 'mongo' in settings")
             .to_string();
-        let request_period: u64 = (1_000_000_000.0 / request_rate) as u64;
-        Config {
+        let ship_cache_path = settings
+            .get("ship_cache")
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| "encyclopedia_cache.json".to_string());
+        let gameparams_path = settings
+            .get("gameparams")
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| "GameParams.json".to_string());
+        let template_reload = match settings.get("template_reload") {
+            Some(x) => x
+                .parse()
+                .expect("Could not parse template_reload as a bool"),
+            None => false,
+        };
+        let metrics_path = settings.get("metrics").map(|x| x.to_string());
+        let histogram_window_days = match settings.get("histogram_window_days") {
+            Some(x) => x
+                .parse()
+                .expect("Could not parse histogram_window_days as an integer"),
+            None => 30,
+        };
+        Ok(Config {
             disable_scraper,
-            api_key,
-            request_period,
+            api_keys,
+            rate_limits,
             mongo_url,
-        }
+            ship_cache_path,
+            gameparams_path,
+            template_reload,
+            metrics_path,
+            histogram_window_days,
+        })
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::Config;
-    use std::collections::HashMap;
-
-    #[test]
-    fn config_parser_works() {
-        let mut settings = HashMap::new();
-        settings.insert("api_key".to_string(), "asdf".to_string());
-        settings.insert("api_request_rate".to_string(), "20".to_string());
-
-        let cfg = Config::from_map(settings);
-        assert_eq!(cfg.api_key, "asdf");
-        assert_eq!(cfg.request_period, 50_000_000);
-    }
+/// Decodes the game's GameParams.data into JSON, for poking around in
+fn export_gameparams(input: &str, output: &str) -> Result<(), Error> {
+    let input = std::io::BufReader::new(std::fs::File::open(input)?);
+    let value = crate::gameparams::decode_data(input)?;
+    let output = std::io::BufWriter::new(std::fs::File::create(output)?);
+    serde_json::to_writer_pretty(output, &value)?;
+    Ok(())
 }
 
 #[tokio::main]
@@ -302,6 +454,21 @@ async fn main() -> Result<(), Error> {
 
     //console_subscriber::init();
 
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|x| x.as_str()) {
+        Some("export-gameparams") if args.len() == 4 => {
+            return export_gameparams(&args[2], &args[3]);
+        }
+        Some(_) => {
+            eprintln!(
+                "Usage: {} [export-gameparams <GameParams.data> <GameParams.json>]",
+                args[0]
+            );
+            std::process::exit(1);
+        }
+        None => {}
+    }
+
     let mut settings = config::Config::default();
     settings
         .merge(config::File::with_name("settings"))
@@ -309,8 +476,8 @@ async fn main() -> Result<(), Error> {
         .merge(config::Environment::with_prefix("STATS"))
         .unwrap();
 
-    let settings: HashMap<String, String> = settings.try_into().unwrap();
-    let cfg = Config::from_map(settings);
+    let settings: HashMap<String, config::Value> = settings.try_into().unwrap();
+    let cfg = Config::from_map(flatten_settings(settings)?)?;
 
     let storage_client = mongodb::Client::with_options(
         mongodb::options::ClientOptions::parse(cfg.mongo_url)
@@ -324,13 +491,13 @@ async fn main() -> Result<(), Error> {
     let collection = db.collection::<database::DetailedStatRecord>("playerstats");
     let stats_count = collection.estimated_document_count(None).await.unwrap();
     info!("DB has {} player+ship entries already", stats_count);
-    if stats_count == 0 {
-        let index = doc! { "account_id": 1 };
-        collection
-            .create_index(mongodb::IndexModel::builder().keys(index).build(), None)
-            .await
-            .expect("Could not create index on playerstats collection");
-    }
+    // Priming sorts by account and lookups filter by it, so make sure the index is there even on
+    // databases from before it was added. Creating an index that already exists does nothing.
+    let index = doc! { "account_id": 1 };
+    collection
+        .create_index(mongodb::IndexModel::builder().keys(index).build(), None)
+        .await
+        .expect("Could not create index on playerstats collection");
 
     let collection = db.collection::<PlayerRecord>("playerids");
     if collection.estimated_document_count(None).await.unwrap() == 0 {
@@ -341,7 +508,17 @@ async fn main() -> Result<(), Error> {
             .expect("Could not create index on playerids collection");
     }
 
-    let histograms = Arc::new(Mutex::new(StatsHistogram::new()));
+    let ships = crate::ships::ShipDb::new(&cfg.ship_cache_path);
+    let metrics = match &cfg.metrics_path {
+        Some(path) => MetricRegistry::load(path),
+        None => MetricRegistry::builtin(),
+    }
+    .expect("Could not load metric definitions");
+    let histograms = Arc::new(StatsHistogram::new(
+        ships.clone(),
+        Arc::new(metrics),
+        crate::histogram::Window::days(cfg.histogram_window_days),
+    ));
 
     {
         let db = db.clone();
@@ -350,83 +527,129 @@ async fn main() -> Result<(), Error> {
             // Prime the histograms with all the current statistics
             info!("Priming histogram with existing DB entries");
             let collection = db.collection::<database::DetailedStatRecord>("playerstats");
-            let mut cursor = collection.find(None, None).await.unwrap();
+            // Sorted by account, so each account's records can be counted together
+            let options = mongodb::options::FindOptions::builder()
+                .sort(doc! { "account_id": 1 })
+                .allow_disk_use(true)
+                .build();
+            let mut cursor = collection.find(None, options).await.unwrap();
             let mut pl = crate::progress_logger::ProgressLogger::new_with_target(
                 "histogram_prime",
                 stats_count as usize,
             );
+
+            // Reading from Mongo is done here, and counting is spread over a worker per core
+            let workers = std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1);
+            let (sender, receiver) =
+                async_channel::bounded::<Vec<database::DetailedStatRecord>>(workers * 4);
+            let workers: Vec<_> = (0..workers)
+                .map(|_| {
+                    let receiver = receiver.clone();
+                    let histograms = histograms.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let mut batch = PrimingBatch::default();
+                        while let Ok(account) = futures::executor::block_on(receiver.recv()) {
+                            histograms.prime_account(&mut batch, account[0].account_id, account);
+                            if batch.records() >= PRIMING_BATCH_RECORDS {
+                                histograms.merge(std::mem::take(&mut batch));
+                            }
+                        }
+                        histograms.merge(batch);
+                    })
+                })
+                .collect();
+
+            let mut account: Vec<database::DetailedStatRecord> = vec![];
             while let Some(statrecord) = cursor.try_next().await.unwrap() {
-                let mut histograms = histograms.lock().unwrap();
-                histograms.increment(statrecord.ship_id, &statrecord.pvp);
-                pl.increment(1);
+                if let Some(first) = account.first() {
+                    if first.account_id != statrecord.account_id {
+                        pl.increment(account.len());
+                        sender.send(std::mem::take(&mut account)).await.unwrap();
+                    }
+                }
+                account.push(statrecord);
+            }
+            if !account.is_empty() {
+                pl.increment(account.len());
+                sender.send(account).await.unwrap();
             }
+            sender.close();
+            for worker in workers {
+                worker.await.unwrap();
+            }
+            tokio::task::spawn_blocking(move || histograms.publish())
+                .await
+                .unwrap();
             info!("Finished priming histograms");
         });
     }
-
-    let ships = crate::ships::ShipDb::new();
+    tokio::spawn(histograms.clone().publish_loop());
 
     info!("Starting app");
-    let client = crate::scraper::WowsClient::new(&cfg.api_key, cfg.request_period);
+    let client = crate::scraper::WowsClient::new(&cfg.api_keys, &cfg.rate_limits);
 
     // Load the cheatsheet
     let cheatsheetdb = {
-        let gameparams: GameParams = {
-            let mut data = vec![];
-            File::open("GameParams.json")
-                .await
-                .expect("Could not open required GameParams.json file")
-                .read_to_end(&mut data)
-                .await?;
-            GameParams::load(&data)?
-        };
-        let ships = ships.clone();
-        CheatsheetDb::from(ships, gameparams)
+        let path = &cfg.gameparams_path;
+        let gameparams = crate::cheatsheet::load_gameparams(path)
+            .await
+            .unwrap_or_else(|e| panic!("Could not load required {}: {:?}", path, e));
+        CheatsheetDb::from(ships.clone(), gameparams, path)
     };
+    {
+        let cheatsheetdb = cheatsheetdb.clone();
+        tokio::spawn(async move {
+            cheatsheetdb.reload_loop().await;
+        });
+    }
 
     // Scrape the WoWS API, and keep the histograms updated
-    if !cfg.disable_scraper {
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+    let poller = if !cfg.disable_scraper {
         let db = db.clone();
         let histograms = histograms.clone();
         let client = client.fork();
-        tokio::spawn(async move {
-            database::poller(&client, db, histograms).await;
-        });
-    }
+        Some(tokio::spawn(async move {
+            database::poller(&client, db, histograms, shutdown_receiver).await;
+        }))
+    } else {
+        None
+    };
 
-    // Periodically (every hour) update the histograms with how big the database is
+    // Keep the ships database up-to-date
     {
+        let ships = ships.clone();
+        let client = client.fork();
         let db = db.clone();
-        let histograms = histograms.clone();
         tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(tokio::time::Duration::from_millis(3600 * 1000)).await;
-
-                let collection = db.collection::<database::DetailedStatRecord>("playerstats");
-                let stats_count = collection.count_documents(None, None).await.unwrap();
-                info!("Database now contains {} entries", stats_count);
-                let mut histograms = histograms.lock().unwrap();
-                histograms.set_database_size(stats_count);
-            }
+            ships.update_loop(client, db).await;
         });
     }
 
-    // Keep the ships database up-to-date
+    // Recompute the realm-wide stats every so often
+    let realm_stats = crate::realm_stats::RealmStatsDb::new();
     {
+        let realm_stats = realm_stats.clone();
+        let db = db.clone();
         let ships = ships.clone();
-        let client = client.fork();
         tokio::spawn(async move {
-            ships.update_loop(client).await;
+            realm_stats.update_loop(db, ships).await;
         });
     }
 
     // Run the web
+    let templates = Templates::new(cfg.template_reload).expect("Could not parse templates");
     let database = db.clone();
     rocket::build()
+        .manage(templates)
         .manage(database)
         .manage(histograms)
         .manage(ships)
         .manage(cheatsheetdb)
+        .manage(realm_stats)
+        .manage(client.fork_interactive())
         .mount(
             "/warshipstats",
             routes![
@@ -434,12 +657,99 @@ async fn main() -> Result<(), Error> {
                 player_stats,
                 player_stats_raw,
                 ship_data,
-                render_cheatsheet
+                ship_params,
+                ship_change_log,
+                realm_stats_page,
+                realm_stats_raw,
+                api_key_usage,
+                render_cheatsheet,
+                export_cheatsheet,
+                cheatsheet_mismatches
             ],
         )
         .launch()
         .await
         .expect("Issue running webserver");
 
+    // Rocket returns once it gets SIGINT/SIGTERM, so let the poller finish up and checkpoint
+    if let Some(poller) = poller {
+        info!("Webserver stopped, waiting for the poller to shut down");
+        shutdown_sender
+            .send(true)
+            .expect("Poller should still be listening for shutdown");
+        poller.await.expect("Poller should not have panicked");
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use std::collections::HashMap;
+
+    #[test]
+    fn config_parser_works() {
+        let mut settings = HashMap::new();
+        settings.insert("api_key".to_string(), "asdf".to_string());
+        settings.insert("api_request_rate".to_string(), "20".to_string());
+        settings.insert("mongo".to_string(), "mongodb://localhost".to_string());
+
+        let cfg = Config::from_map(settings).unwrap();
+        assert_eq!(cfg.api_keys, vec!["asdf".to_string()]);
+        assert_eq!(cfg.rate_limits.background_ceiling, 20.0);
+        assert_eq!(cfg.rate_limits.interactive_ceiling, 2.0);
+        assert_eq!(cfg.rate_limits.floor, 1.0);
+    }
+
+    #[test]
+    fn config_parses_multiple_api_keys() {
+        let mut settings = HashMap::new();
+        settings.insert("api_key".to_string(), "asdf, qwer,".to_string());
+        settings.insert("api_request_rate".to_string(), "20".to_string());
+        settings.insert("mongo".to_string(), "mongodb://localhost".to_string());
+
+        let cfg = Config::from_map(settings).unwrap();
+        assert_eq!(cfg.api_keys, vec!["asdf".to_string(), "qwer".to_string()]);
+    }
+
+    #[test]
+    fn rejects_rates_the_limiter_cant_use() {
+        for (key, rate) in [
+            ("api_request_rate_floor", "0"),
+            ("api_interactive_request_rate", "-2"),
+            ("api_request_rate", "NaN"),
+            ("api_request_rate", "fast"),
+        ] {
+            let mut settings = HashMap::new();
+            settings.insert("api_key".to_string(), "asdf".to_string());
+            settings.insert("api_request_rate".to_string(), "20".to_string());
+            settings.insert("mongo".to_string(), "mongodb://localhost".to_string());
+            settings.insert(key.to_string(), rate.to_string());
+            match Config::from_map(settings) {
+                Err(super::Error::Settings { key: bad, .. }) => assert_eq!(bad, key),
+                Err(e) => panic!("expected a settings error for {}, got {:?}", key, e),
+                Ok(_) => panic!("accepted {} = {}", key, rate),
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_settings_name_the_key() {
+        let parse = |toml: &str| {
+            let mut settings = config::Config::default();
+            settings
+                .merge(config::File::from_str(toml, config::FileFormat::Toml))
+                .unwrap();
+            super::flatten_settings(settings.try_into().unwrap())
+        };
+        let settings =
+            parse("api_key = [\"asdf\", \"qwer\"]\nmongo = \"mongodb://localhost\"").unwrap();
+        assert_eq!(settings["api_key"], "asdf,qwer");
+
+        match parse("api_key = [[\"asdf\"]]") {
+            Err(super::Error::Settings { key, .. }) => assert_eq!(key, "api_key"),
+            other => panic!("expected a settings error, got {:?}", other),
+        }
+    }
+}
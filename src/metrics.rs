@@ -0,0 +1,377 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::wows_data::DetailedStats;
+
+/// The metric definitions used when no `metrics` file is configured
+const BUILTIN_METRICS: &str = include_str!("../metrics.toml");
+
+fn default_min_battles() -> u32 {
+    11
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_relative_sd() -> f64 {
+    1.0
+}
+
+/// z-score for a 95% confidence interval
+const Z_95: f64 = 1.96;
+
+/// Stats are only flagged as reliable once the winrate is pinned down to within this much
+const MAX_RELIABLE_WINRATE_WIDTH: f64 = 0.15;
+
+/// Whether there are enough battles behind a set of stats to take them at face value
+pub fn is_reliable(intervals: &HashMap<String, Interval>) -> bool {
+    intervals
+        .get("winrate")
+        .map(|x| x.high - x.low <= MAX_RELIABLE_WINRATE_WIDTH)
+        .unwrap_or(false)
+}
+
+/// How to put a confidence interval on a metric
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IntervalKind {
+    /// A proportion of successes, e.g. wins out of battles or hits out of shots
+    Wilson,
+    /// A per-battle average, whose per-battle standard deviation is assumed to be `relative_sd`
+    /// times the mean
+    Mean,
+}
+
+/// A 95% confidence interval on a metric
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct Interval {
+    pub low: f64,
+    pub high: f64,
+    /// The interval rests on a guessed standard deviation rather than one measured from the
+    /// player's battles, since the API only gives us totals. It's a rough guide, not a real CI.
+    pub assumed_variance: bool,
+}
+
+impl Interval {
+    /// Wilson score interval for `successes` out of `trials`
+    pub fn wilson(successes: f64, trials: f64) -> Option<Self> {
+        if trials <= 0.0 || successes < 0.0 || successes > trials {
+            return None;
+        }
+        let p = successes / trials;
+        let z2 = Z_95 * Z_95;
+        let denominator = 1.0 + z2 / trials;
+        let center = (p + z2 / (2.0 * trials)) / denominator;
+        let spread =
+            Z_95 * (p * (1.0 - p) / trials + z2 / (4.0 * trials * trials)).sqrt() / denominator;
+        Some(Self {
+            low: (center - spread).max(0.0),
+            high: (center + spread).min(1.0),
+            assumed_variance: false,
+        })
+    }
+
+    /// Normal interval around a mean of `samples` values, none of which can be negative, given an
+    /// assumed standard deviation
+    pub fn mean(mean: f64, sd: f64, samples: f64) -> Option<Self> {
+        if samples <= 0.0 || !mean.is_finite() || !sd.is_finite() {
+            return None;
+        }
+        let standard_error = sd / samples.sqrt();
+        Some(Self {
+            low: (mean - Z_95 * standard_error).max(0.0),
+            high: mean + Z_95 * standard_error,
+            assumed_variance: true,
+        })
+    }
+}
+
+/// Reads a single field of the detailed stats
+type Field = fn(&DetailedStats) -> f64;
+
+/// Looks up the accessor for a dotted field path, e.g. "main_battery.hits"
+fn field(path: &str) -> Option<Field> {
+    macro_rules! fields {
+        ($($name:ident),*; $($battery:ident),*) => {
+            $(
+                if path == stringify!($name) {
+                    return Some(|stats| stats.$name as f64);
+                }
+            )*
+            $(
+                if path == concat!(stringify!($battery), ".max_frags_battle") {
+                    return Some(|stats| stats.$battery.max_frags_battle as f64);
+                }
+                if path == concat!(stringify!($battery), ".frags") {
+                    return Some(|stats| stats.$battery.frags as f64);
+                }
+                if path == concat!(stringify!($battery), ".hits") {
+                    return Some(|stats| stats.$battery.hits as f64);
+                }
+                if path == concat!(stringify!($battery), ".shots") {
+                    return Some(|stats| stats.$battery.shots as f64);
+                }
+            )*
+        };
+    }
+    fields!(
+        max_xp, damage_to_buildings, suppressions_count, max_damage_scouting, art_agro,
+        ships_spotted, xp, survived_battles, dropped_capture_points,
+        max_damage_dealt_to_buildings, torpedo_agro, draws, battles_since_510, planes_killed,
+        battles, max_ships_spotted, team_capture_points, frags, damage_scouting, max_total_agro,
+        max_frags_battle, capture_points, survived_wins, max_damage_dealt, wins, losses,
+        damage_dealt, max_planes_killed, max_suppressions_count, team_dropped_capture_points,
+        battles_since_512;
+        main_battery, second_battery, ramming, torpedoes, aircraft
+    );
+    None
+}
+
+/// One statistic we keep histograms of, computed as `value [/ per] [/ battles]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricDef {
+    pub key: String,
+    /// Dotted path to a field of the detailed stats, e.g. "main_battery.hits"
+    pub value: String,
+    /// Field to divide by, for ratios such as hit rate
+    #[serde(default)]
+    pub per: Option<String>,
+    #[serde(default)]
+    pub per_battle: bool,
+    /// Starting histogram range, which grows as larger values come in
+    pub initial_max: f64,
+    /// Players with fewer battles on the ship don't count towards the histogram
+    #[serde(default = "default_min_battles")]
+    pub min_battles: u32,
+    #[serde(default = "default_true")]
+    pub higher_is_better: bool,
+    #[serde(default)]
+    pub interval: Option<IntervalKind>,
+    /// For `mean` intervals, our guess at the per-battle standard deviation relative to the mean
+    #[serde(default = "default_relative_sd")]
+    pub relative_sd: f64,
+}
+
+#[derive(Deserialize)]
+struct MetricsFile {
+    metric: Vec<MetricDef>,
+}
+
+/// A metric along with the accessors for its fields, so they're only looked up once
+struct Metric {
+    def: MetricDef,
+    value: Field,
+    per: Option<Field>,
+}
+
+pub struct MetricRegistry {
+    metrics: Vec<Metric>,
+}
+
+impl MetricRegistry {
+    fn from_config(source: config::Config) -> Result<Self, Error> {
+        let file: MetricsFile = source.try_into().map_err(|err| Error::Metrics { err })?;
+        let mut seen = std::collections::HashSet::new();
+        for metric in file.metric.iter() {
+            if !seen.insert(metric.key.as_str()) {
+                return Err(Error::Metrics {
+                    err: config::ConfigError::Message(format!(
+                        "metric {} is defined twice",
+                        metric.key
+                    )),
+                });
+            }
+        }
+        let unknown = |path: &str, key: &str| Error::Metrics {
+            err: config::ConfigError::Message(format!(
+                "metric {} refers to unknown field {}",
+                key, path
+            )),
+        };
+        let metrics = file
+            .metric
+            .into_iter()
+            .map(|def| {
+                let value = field(&def.value).ok_or_else(|| unknown(&def.value, &def.key))?;
+                let per = match &def.per {
+                    Some(per) => Some(field(per).ok_or_else(|| unknown(per, &def.key))?),
+                    None => None,
+                };
+                Ok(Metric { def, value, per })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { metrics })
+    }
+
+    pub fn builtin() -> Result<Self, Error> {
+        let mut source = config::Config::default();
+        source
+            .merge(config::File::from_str(
+                BUILTIN_METRICS,
+                config::FileFormat::Toml,
+            ))
+            .map_err(|err| Error::Metrics { err })?;
+        Self::from_config(source)
+    }
+
+    /// Loads metric definitions from a file in any format the settings file can be in
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let mut source = config::Config::default();
+        source
+            .merge(config::File::with_name(path))
+            .map_err(|err| Error::Metrics { err })?;
+        Self::from_config(source)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&MetricDef> {
+        self.metrics
+            .iter()
+            .map(|metric| &metric.def)
+            .find(|metric| metric.key == key)
+    }
+
+    /// Computes every metric for the stats. Metrics whose fields are missing, or that come out as
+    /// NaN or infinite (e.g. hit rate with no shots), are left out.
+    pub fn extract(&self, stats: &DetailedStats) -> HashMap<String, f64> {
+        self.extract_qualifying(stats, None)
+    }
+
+    /// Like `extract`, but only the metrics whose `min_battles` the stats meet
+    pub fn qualifying(&self, stats: &DetailedStats) -> HashMap<String, f64> {
+        self.extract_qualifying(stats, Some(stats.battles))
+    }
+
+    /// 95% confidence intervals for the metrics that define how to compute one
+    pub fn intervals(&self, stats: &DetailedStats) -> HashMap<String, Interval> {
+        let nbattles = stats.battles as f64;
+        self.metrics
+            .iter()
+            .filter_map(|metric| {
+                let value = (metric.value)(stats);
+                let interval = match metric.def.interval? {
+                    IntervalKind::Wilson => {
+                        let trials = match metric.per {
+                            Some(per) => per(stats),
+                            None => nbattles,
+                        };
+                        Interval::wilson(value, trials)?
+                    }
+                    IntervalKind::Mean => {
+                        let mean = value / nbattles;
+                        Interval::mean(mean, mean * metric.def.relative_sd, nbattles)?
+                    }
+                };
+                Some((metric.def.key.to_owned(), interval))
+            })
+            .collect()
+    }
+
+    fn extract_qualifying(
+        &self,
+        stats: &DetailedStats,
+        battles: Option<u32>,
+    ) -> HashMap<String, f64> {
+        let nbattles = stats.battles as f64;
+        self.metrics
+            .iter()
+            .filter(|metric| battles.map(|x| x >= metric.def.min_battles).unwrap_or(true))
+            .map(|metric| {
+                let mut value = (metric.value)(stats);
+                if let Some(per) = metric.per {
+                    value /= per(stats);
+                }
+                if metric.def.per_battle {
+                    value /= nbattles;
+                }
+                (metric.def.key.to_owned(), value)
+            })
+            .filter(|(_, value)| value.is_finite())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_metrics_cover_the_templates() {
+        let registry = MetricRegistry::builtin().unwrap();
+        for key in [
+            "damage_dealt",
+            "frags",
+            "main_battery.hitrate",
+            "main_battery.shots",
+            "main_battery.hits",
+            "winrate",
+            "xp",
+        ] {
+            assert!(registry.get(key).is_some(), "{} is missing", key);
+        }
+        assert!(!registry.get("losses").unwrap().higher_is_better);
+        assert_eq!(registry.get("xp").unwrap().min_battles, 11);
+    }
+
+    #[test]
+    fn extract_ignores_min_battles() {
+        let registry = MetricRegistry::builtin().unwrap();
+        let mut stats = DetailedStats::zeroed();
+        stats.battles = 5;
+        stats.damage_dealt = 150_000;
+        assert_eq!(registry.extract(&stats)["damage_dealt"], 30_000.0);
+        assert!(!registry.qualifying(&stats).contains_key("damage_dealt"));
+    }
+
+    #[test]
+    fn rejects_duplicate_metrics() {
+        let mut source = config::Config::default();
+        source
+            .merge(config::File::from_str(
+                "[[metric]]\nkey = \"xp\"\nvalue = \"xp\"\ninitial_max = 1.0\n\n\
+                 [[metric]]\nkey = \"xp\"\nvalue = \"max_xp\"\ninitial_max = 1.0\n",
+                config::FileFormat::Toml,
+            ))
+            .unwrap();
+        assert!(MetricRegistry::from_config(source).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let mut source = config::Config::default();
+        source
+            .merge(config::File::from_str(
+                "[[metric]]\nkey = \"hitrate\"\nvalue = \"main_battery.hits\"\n\
+                 per = \"main_battery.shotz\"\ninitial_max = 1.0\n",
+                config::FileFormat::Toml,
+            ))
+            .unwrap();
+        match MetricRegistry::from_config(source) {
+            Err(err) => assert!(err.to_string().contains("main_battery.shotz"), "{}", err),
+            Ok(_) => panic!("Loaded a metric with an unknown field"),
+        }
+    }
+
+    #[test]
+    fn intervals_narrow_with_more_battles() {
+        let few = Interval::wilson(11.0, 12.0).unwrap();
+        let many = Interval::wilson(1833.0, 2000.0).unwrap();
+        assert!(few.low < 0.7 && few.high > 0.95);
+        assert!(many.low > 0.9 && many.high < 0.93);
+
+        // Extremes stay within [0, 1] and still admit some doubt
+        let perfect = Interval::wilson(5.0, 5.0).unwrap();
+        assert_eq!(perfect.high, 1.0);
+        assert!(perfect.low < 0.6);
+        assert!(Interval::wilson(1.0, 0.0).is_none());
+
+        let damage = Interval::mean(50_000.0, 50_000.0, 100.0).unwrap();
+        assert!((damage.low - 40_200.0).abs() < 1.0);
+        assert!((damage.high - 59_800.0).abs() < 1.0);
+
+        // Only proportions can be worked out exactly from the totals
+        assert!(damage.assumed_variance);
+        assert!(!many.assumed_variance);
+    }
+}
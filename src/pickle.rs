@@ -0,0 +1,590 @@
+//! Just enough of Python's pickle format to read the game's `GameParams.data`.
+//!
+//! Instances of the game's classes are turned into maps of their attributes, and tuples and sets
+//! into lists, which is the same shape the usual GameParams.json converters produce.
+
+use serde_json::{Map, Number, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Read;
+use std::rc::Rc;
+
+use crate::error::Error;
+
+/// Anything nested deeper than this is almost certainly a reference cycle
+const MAX_DEPTH: usize = 256;
+
+#[derive(Clone)]
+enum PickleValue {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    /// Lists, tuples and sets
+    List(Rc<RefCell<Vec<PickleValue>>>),
+    /// Dicts, and the attributes of objects
+    Dict(Rc<RefCell<Vec<(PickleValue, PickleValue)>>>),
+    /// A class or function, which is only ever used to construct something
+    Global(String),
+}
+
+impl PickleValue {
+    fn list(items: Vec<PickleValue>) -> Self {
+        Self::List(Rc::new(RefCell::new(items)))
+    }
+
+    fn dict() -> Self {
+        Self::Dict(Rc::new(RefCell::new(vec![])))
+    }
+
+    fn key(&self) -> String {
+        match self {
+            Self::String(s) => s.clone(),
+            Self::Int(i) => i.to_string(),
+            Self::Float(f) => f.to_string(),
+            Self::Bool(b) => if *b { "True" } else { "False" }.to_string(),
+            Self::None => "None".to_string(),
+            Self::Global(name) => name.clone(),
+            Self::List(items) => {
+                let items: Vec<_> = items.borrow().iter().map(|x| x.key()).collect();
+                format!("({})", items.join(", "))
+            }
+            Self::Dict(_) => "?".to_string(),
+        }
+    }
+
+    fn to_json(&self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("structure is nested too deeply".to_string());
+        }
+        Ok(match self {
+            Self::None => Value::Null,
+            Self::Bool(b) => Value::Bool(*b),
+            Self::Int(i) => Value::Number((*i).into()),
+            Self::Float(f) => Number::from_f64(*f)
+                .map(Value::Number)
+                .unwrap_or(Value::Null),
+            Self::String(s) => Value::String(s.clone()),
+            Self::Global(name) => Value::String(name.clone()),
+            Self::List(items) => Value::Array(
+                items
+                    .borrow()
+                    .iter()
+                    .map(|x| x.to_json(depth + 1))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Self::Dict(items) => {
+                let mut map = Map::new();
+                for (k, v) in items.borrow().iter() {
+                    map.insert(k.key(), v.to_json(depth + 1)?);
+                }
+                Value::Object(map)
+            }
+        })
+    }
+}
+
+struct Unpickler<R> {
+    reader: R,
+    offset: usize,
+    stack: Vec<PickleValue>,
+    marks: Vec<usize>,
+    memo: HashMap<u32, PickleValue>,
+}
+
+impl<R: Read> Unpickler<R> {
+    fn error(&self, reason: &str) -> Error {
+        Error::Unpickle {
+            offset: self.offset,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>, Error> {
+        // The length comes from the file, so don't trust it enough to allocate it all up front
+        let mut buf = vec![];
+        (&mut self.reader)
+            .take(n as u64)
+            .read_to_end(&mut buf)
+            .map_err(|_| self.error("unexpected end of data"))?;
+        if buf.len() < n {
+            return Err(self.error("unexpected end of data"));
+        }
+        self.offset += n;
+        Ok(buf)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut buf = [0; N];
+        self.reader
+            .read_exact(&mut buf)
+            .map_err(|_| self.error("unexpected end of data"))?;
+        self.offset += N;
+        Ok(buf)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = vec![];
+        loop {
+            match self.read_u8()? {
+                b'\n' => break,
+                c => line.push(c),
+            }
+        }
+        Ok(latin1(&line))
+    }
+
+    fn read_utf8(&mut self, n: usize) -> Result<String, Error> {
+        let bytes = self.read_bytes(n)?;
+        String::from_utf8(bytes).map_err(|_| self.error("invalid UTF-8 string"))
+    }
+
+    fn pop(&mut self) -> Result<PickleValue, Error> {
+        match self.stack.pop() {
+            Some(x) => Ok(x),
+            None => Err(self.error("stack underflow")),
+        }
+    }
+
+    fn top(&mut self) -> Result<&PickleValue, Error> {
+        match self.stack.last() {
+            Some(x) => Ok(x),
+            None => Err(self.error("stack underflow")),
+        }
+    }
+
+    /// Pops everything back to the most recent mark
+    fn pop_mark(&mut self) -> Result<Vec<PickleValue>, Error> {
+        let mark = match self.marks.pop() {
+            Some(x) => x,
+            None => return Err(self.error("no mark on the stack")),
+        };
+        if mark > self.stack.len() {
+            return Err(self.error("mark is past the end of the stack"));
+        }
+        let items = self.stack.split_off(mark);
+        Ok(items)
+    }
+
+    fn memoize(&mut self, index: u32) -> Result<(), Error> {
+        let value = self.top()?.clone();
+        self.memo.insert(index, value);
+        Ok(())
+    }
+
+    fn recall(&mut self, index: u32) -> Result<(), Error> {
+        match self.memo.get(&index) {
+            Some(x) => {
+                let x = x.clone();
+                self.stack.push(x);
+                Ok(())
+            }
+            None => Err(self.error("reference to a missing memo entry")),
+        }
+    }
+
+    fn add_items(&mut self, items: Vec<PickleValue>) -> Result<(), Error> {
+        match self.top()? {
+            PickleValue::List(list) => {
+                list.borrow_mut().extend(items);
+                Ok(())
+            }
+            _ => Err(self.error("appending to something that isn't a list")),
+        }
+    }
+
+    fn set_items(&mut self, items: Vec<PickleValue>) -> Result<(), Error> {
+        if !items.len().is_multiple_of(2) {
+            return Err(self.error("odd number of dict items"));
+        }
+        match self.top()? {
+            PickleValue::Dict(dict) => {
+                let mut dict = dict.borrow_mut();
+                let mut items = items.into_iter();
+                while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                    dict.push((k, v));
+                }
+                Ok(())
+            }
+            _ => Err(self.error("setting items on something that isn't a dict")),
+        }
+    }
+
+    /// Calls a class or function. Sets are the only thing whose contents we care about, anything
+    /// else becomes an object whose attributes are filled in by a later BUILD.
+    fn construct(&self, callable: &PickleValue, args: &PickleValue) -> PickleValue {
+        let name = match callable {
+            PickleValue::Global(name) => name.as_str(),
+            _ => "",
+        };
+        match (name.rsplit('.').next(), args) {
+            (Some("set") | Some("frozenset"), PickleValue::List(args)) => {
+                match args.borrow().first() {
+                    Some(PickleValue::List(items)) => PickleValue::list(items.borrow().clone()),
+                    _ => PickleValue::list(vec![]),
+                }
+            }
+            _ => PickleValue::dict(),
+        }
+    }
+
+    fn build(&mut self, state: PickleValue) -> Result<(), Error> {
+        let target = match self.top()? {
+            PickleValue::Dict(dict) => dict.clone(),
+            // We don't know how to set attributes on anything else, so drop them
+            _ => return Ok(()),
+        };
+        // State is either a dict of attributes, or a (dict, slots) pair
+        let states = match state {
+            PickleValue::List(items) => items.borrow().clone(),
+            state => vec![state],
+        };
+        for state in states {
+            if let PickleValue::Dict(state) = state {
+                target.borrow_mut().extend(state.borrow().iter().cloned());
+            }
+        }
+        Ok(())
+    }
+
+    fn run(mut self) -> Result<PickleValue, Error> {
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                // PROTO
+                0x80 => {
+                    self.read_u8()?;
+                }
+                // FRAME
+                0x95 => {
+                    self.read_array::<8>()?;
+                }
+                // STOP
+                b'.' => return self.pop(),
+                // MARK
+                b'(' => self.marks.push(self.stack.len()),
+                // POP
+                b'0' => {
+                    self.pop()?;
+                }
+                // POP_MARK
+                b'1' => {
+                    self.pop_mark()?;
+                }
+                // DUP
+                b'2' => {
+                    let top = self.top()?.clone();
+                    self.stack.push(top);
+                }
+                // NONE, NEWTRUE, NEWFALSE
+                b'N' => self.stack.push(PickleValue::None),
+                0x88 => self.stack.push(PickleValue::Bool(true)),
+                0x89 => self.stack.push(PickleValue::Bool(false)),
+                // INT, which is also how protocol 0 writes bools
+                b'I' => {
+                    let line = self.read_line()?;
+                    let value = match line.as_str() {
+                        "00" => PickleValue::Bool(false),
+                        "01" => PickleValue::Bool(true),
+                        line => {
+                            PickleValue::Int(line.parse().map_err(|_| self.error("invalid INT"))?)
+                        }
+                    };
+                    self.stack.push(value);
+                }
+                // LONG
+                b'L' => {
+                    let line = self.read_line()?;
+                    let value = line
+                        .trim_end_matches('L')
+                        .parse()
+                        .map_err(|_| self.error("invalid LONG"))?;
+                    self.stack.push(PickleValue::Int(value));
+                }
+                // BININT, BININT1, BININT2
+                b'J' => {
+                    let value = i32::from_le_bytes(self.read_array()?);
+                    self.stack.push(PickleValue::Int(value.into()));
+                }
+                b'K' => {
+                    let value = self.read_u8()?;
+                    self.stack.push(PickleValue::Int(value.into()));
+                }
+                b'M' => {
+                    let value = u16::from_le_bytes(self.read_array()?);
+                    self.stack.push(PickleValue::Int(value.into()));
+                }
+                // LONG1, LONG4
+                0x8a | 0x8b => {
+                    let n = if opcode == 0x8a {
+                        self.read_u8()? as usize
+                    } else {
+                        self.read_u32()? as usize
+                    };
+                    let bytes = self.read_bytes(n)?;
+                    self.stack.push(long_from_bytes(&bytes));
+                }
+                // FLOAT, BINFLOAT
+                b'F' => {
+                    let line = self.read_line()?;
+                    let value = line.parse().map_err(|_| self.error("invalid FLOAT"))?;
+                    self.stack.push(PickleValue::Float(value));
+                }
+                b'G' => {
+                    let value = f64::from_be_bytes(self.read_array()?);
+                    self.stack.push(PickleValue::Float(value));
+                }
+                // BINSTRING, SHORT_BINSTRING, BINBYTES, SHORT_BINBYTES. These are Python 2 strings,
+                // which we read as latin1 like Python 3's unpickler does.
+                b'T' | b'U' | b'B' | b'C' => {
+                    let n = match opcode {
+                        b'U' | b'C' => self.read_u8()? as usize,
+                        _ => self.read_u32()? as usize,
+                    };
+                    let bytes = self.read_bytes(n)?;
+                    self.stack.push(PickleValue::String(latin1(&bytes)));
+                }
+                // BINUNICODE, SHORT_BINUNICODE, BINUNICODE8
+                b'X' => {
+                    let n = self.read_u32()? as usize;
+                    let s = self.read_utf8(n)?;
+                    self.stack.push(PickleValue::String(s));
+                }
+                0x8c => {
+                    let n = self.read_u8()? as usize;
+                    let s = self.read_utf8(n)?;
+                    self.stack.push(PickleValue::String(s));
+                }
+                0x8d => {
+                    let n = u64::from_le_bytes(self.read_array()?) as usize;
+                    let s = self.read_utf8(n)?;
+                    self.stack.push(PickleValue::String(s));
+                }
+                // EMPTY_LIST, EMPTY_TUPLE, EMPTY_SET, EMPTY_DICT
+                b']' | b')' | 0x8f => self.stack.push(PickleValue::list(vec![])),
+                b'}' => self.stack.push(PickleValue::dict()),
+                // LIST, TUPLE, FROZENSET
+                b'l' | b't' | 0x91 => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(PickleValue::list(items));
+                }
+                // TUPLE1, TUPLE2, TUPLE3
+                0x85..=0x87 => {
+                    let n = (opcode - 0x84) as usize;
+                    if self.stack.len() < n {
+                        return Err(self.error("stack underflow"));
+                    }
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(PickleValue::list(items));
+                }
+                // DICT
+                b'd' => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(PickleValue::dict());
+                    self.set_items(items)?;
+                }
+                // APPEND, APPENDS, ADDITEMS
+                b'a' => {
+                    let item = self.pop()?;
+                    self.add_items(vec![item])?;
+                }
+                b'e' | 0x90 => {
+                    let items = self.pop_mark()?;
+                    self.add_items(items)?;
+                }
+                // SETITEM, SETITEMS
+                b's' => {
+                    let v = self.pop()?;
+                    let k = self.pop()?;
+                    self.set_items(vec![k, v])?;
+                }
+                b'u' => {
+                    let items = self.pop_mark()?;
+                    self.set_items(items)?;
+                }
+                // PUT, BINPUT, LONG_BINPUT, MEMOIZE
+                b'p' => {
+                    let line = self.read_line()?;
+                    let index = line.parse().map_err(|_| self.error("invalid PUT"))?;
+                    self.memoize(index)?;
+                }
+                b'q' => {
+                    let index = self.read_u8()?.into();
+                    self.memoize(index)?;
+                }
+                b'r' => {
+                    let index = self.read_u32()?;
+                    self.memoize(index)?;
+                }
+                0x94 => {
+                    let index = self.memo.len() as u32;
+                    self.memoize(index)?;
+                }
+                // GET, BINGET, LONG_BINGET
+                b'g' => {
+                    let line = self.read_line()?;
+                    let index = line.parse().map_err(|_| self.error("invalid GET"))?;
+                    self.recall(index)?;
+                }
+                b'h' => {
+                    let index = self.read_u8()?.into();
+                    self.recall(index)?;
+                }
+                b'j' => {
+                    let index = self.read_u32()?;
+                    self.recall(index)?;
+                }
+                // GLOBAL, STACK_GLOBAL
+                b'c' => {
+                    let module = self.read_line()?;
+                    let name = self.read_line()?;
+                    self.stack
+                        .push(PickleValue::Global(format!("{}.{}", module, name)));
+                }
+                0x93 => {
+                    let name = self.pop()?.key();
+                    let module = self.pop()?.key();
+                    self.stack
+                        .push(PickleValue::Global(format!("{}.{}", module, name)));
+                }
+                // REDUCE, NEWOBJ, NEWOBJ_EX, OBJ, INST
+                b'R' | 0x81 => {
+                    let args = self.pop()?;
+                    let callable = self.pop()?;
+                    let value = self.construct(&callable, &args);
+                    self.stack.push(value);
+                }
+                0x92 => {
+                    self.pop()?;
+                    let args = self.pop()?;
+                    let callable = self.pop()?;
+                    let value = self.construct(&callable, &args);
+                    self.stack.push(value);
+                }
+                b'o' => {
+                    let mut items = self.pop_mark()?;
+                    if items.is_empty() {
+                        return Err(self.error("OBJ without a class"));
+                    }
+                    let callable = items.remove(0);
+                    let value = self.construct(&callable, &PickleValue::list(items));
+                    self.stack.push(value);
+                }
+                b'i' => {
+                    let module = self.read_line()?;
+                    let name = self.read_line()?;
+                    let items = self.pop_mark()?;
+                    let callable = PickleValue::Global(format!("{}.{}", module, name));
+                    let value = self.construct(&callable, &PickleValue::list(items));
+                    self.stack.push(value);
+                }
+                // BUILD
+                b'b' => {
+                    let state = self.pop()?;
+                    self.build(state)?;
+                }
+                _ => {
+                    return Err(self.error(&format!("unsupported opcode 0x{:02x}", opcode)));
+                }
+            }
+        }
+    }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&c| c as char).collect()
+}
+
+/// Little-endian two's complement. Anything that doesn't fit in an i64 is approximated.
+fn long_from_bytes(bytes: &[u8]) -> PickleValue {
+    if bytes.is_empty() {
+        return PickleValue::Int(0);
+    }
+    let negative = bytes[bytes.len() - 1] & 0x80 != 0;
+    if bytes.len() <= 8 {
+        let mut buf = if negative { [0xff; 8] } else { [0; 8] };
+        buf[..bytes.len()].copy_from_slice(bytes);
+        return PickleValue::Int(i64::from_le_bytes(buf));
+    }
+    let mut value = 0.0;
+    for &b in bytes.iter().rev() {
+        value = value * 256.0 + b as f64;
+    }
+    if negative {
+        value -= 2f64.powi(8 * bytes.len() as i32);
+    }
+    PickleValue::Float(value)
+}
+
+/// Decodes a pickle into JSON.
+pub fn unpickle<R: Read>(reader: R) -> Result<Value, Error> {
+    let unpickler = Unpickler {
+        reader,
+        offset: 0,
+        stack: vec![],
+        marks: vec![],
+        memo: HashMap::new(),
+    };
+    let value = unpickler.run()?;
+    value
+        .to_json(0)
+        .map_err(|reason| Error::Unpickle { offset: 0, reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unpickle;
+
+    #[test]
+    fn unpickles_objects_as_maps() {
+        // class GPData(object): pass
+        // o = GPData(); o.typeinfo = GPData(); o.typeinfo.type = 'Ship'
+        // o.level = 5; o.maxDist = 15000.0; o.tags = set(['x']); o.big = 2**40; o.neg = -3
+        // pickle.dumps({'PASC001': (o,)}, 2)
+        let data = b"\x80\x02}q\x00X\x07\x00\x00\x00PASC001q\x01c__main__\x0aGPData\x0aq\x02)\x81q\x03}q\x04(X\x08\x00\x00\x00typeinfoq\x05h\x02)\x81q\x06}q\x07X\x04\x00\x00\x00typeq\x08X\x04\x00\x00\x00Shipq\x09sbX\x05\x00\x00\x00levelq\x0aK\x05X\x07\x00\x00\x00maxDistq\x0bG@\xcdL\x00\x00\x00\x00\x00X\x04\x00\x00\x00tagsq\x0cc__builtin__\x0aset\x0aq\x0d]q\x0eX\x01\x00\x00\x00xq\x0fa\x85q\x10Rq\x11X\x03\x00\x00\x00bigq\x12\x8a\x06\x00\x00\x00\x00\x00\x01X\x03\x00\x00\x00negq\x13J\xfd\xff\xff\xffub\x85q\x14s.";
+        assert_eq!(
+            unpickle(&data[..]).unwrap(),
+            serde_json::json!({
+                "PASC001": [{
+                    "typeinfo": {"type": "Ship"},
+                    "level": 5,
+                    "maxDist": 15000.0,
+                    "tags": ["x"],
+                    "big": 1099511627776u64,
+                    "neg": -3,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn unpickles_protocol_4() {
+        // pickle.dumps([{'a': (1, 2.5, None, True)}, {'a'}], 4)
+        let data = b"\x80\x04\x95\x22\x00\x00\x00\x00\x00\x00\x00]\x94(}\x94\x8c\x01a\x94(K\x01G@\x04\x00\x00\x00\x00\x00\x00N\x88t\x94s\x8f\x94(h\x02\x90e.";
+        assert_eq!(
+            unpickle(&data[..]).unwrap(),
+            serde_json::json!([{"a": [1, 2.5, null, true]}, ["a"]])
+        );
+    }
+
+    #[test]
+    fn reports_truncated_data() {
+        assert!(unpickle(&b"\x80\x02}q\x00U\x07PASC"[..]).is_err());
+    }
+
+    #[test]
+    fn reports_oversized_lengths() {
+        // BINBYTES and BINUNICODE8 claiming far more data than there is
+        assert!(unpickle(&b"\x80\x02B\xff\xff\xff\xffPASC"[..]).is_err());
+        assert!(unpickle(&b"\x80\x04\x8d\xff\xff\xff\xff\xff\xff\xff\x7fPASC"[..]).is_err());
+    }
+}
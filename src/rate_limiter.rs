@@ -0,0 +1,126 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// Each successful request raises the rate by this much divided by the current rate, so a healthy
+/// limiter climbs by roughly this many requests/sec every second.
+const ADDITIVE_INCREASE: f64 = 0.1;
+
+/// How much the rate is cut when the API tells us to slow down
+const MULTIPLICATIVE_DECREASE: f64 = 0.5;
+
+/// Requests which were already in flight when we got throttled will likely be throttled too, so
+/// only back off once per this period.
+const DECREASE_COOLDOWN: Duration = Duration::from_secs(1);
+
+struct LimiterState {
+    rate: f64,
+    next_slot: Instant,
+    last_decrease: Option<Instant>,
+}
+
+/// An AIMD (additive increase, multiplicative decrease) rate limiter. The rate starts at the
+/// ceiling, is halved whenever the API reports that we're going too fast, and slowly climbs back
+/// up while requests succeed. The rate never leaves the `[floor, ceiling]` range.
+pub struct AdaptiveRateLimiter {
+    label: String,
+    floor: f64,
+    ceiling: f64,
+    state: Mutex<LimiterState>,
+}
+
+impl AdaptiveRateLimiter {
+    /// `floor` and `ceiling` are in requests per second.
+    pub fn new(label: &str, floor: f64, ceiling: f64) -> Self {
+        let floor = floor.min(ceiling);
+        Self {
+            label: label.to_string(),
+            floor,
+            ceiling,
+            state: Mutex::new(LimiterState {
+                rate: ceiling,
+                next_slot: Instant::now(),
+                last_decrease: None,
+            }),
+        }
+    }
+
+    /// Waits until we're allowed to send another request.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let slot = state.next_slot.max(now);
+            state.next_slot = slot + Duration::from_secs_f64(1.0 / state.rate);
+            slot - now
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = (state.rate + ADDITIVE_INCREASE / state.rate).min(self.ceiling);
+    }
+
+    pub fn on_throttled(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if let Some(last_decrease) = state.last_decrease {
+            if now.duration_since(last_decrease) < DECREASE_COOLDOWN {
+                return;
+            }
+        }
+        state.rate = (state.rate * MULTIPLICATIVE_DECREASE).max(self.floor);
+        state.last_decrease = Some(now);
+        warn!(
+            "Throttled by the WoWS API, slowing {} requests to {:.2}/sec",
+            self.label, state.rate
+        );
+    }
+
+    /// When the next request will be allowed to go out
+    pub fn next_slot(&self) -> Instant {
+        self.state.lock().unwrap().next_slot
+    }
+
+    /// The current rate, in requests per second
+    pub fn rate(&self) -> f64 {
+        self.state.lock().unwrap().rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AdaptiveRateLimiter;
+
+    #[test]
+    fn backs_off_and_recovers_within_bounds() {
+        let limiter = AdaptiveRateLimiter::new("test", 1.0, 10.0);
+        assert_eq!(limiter.rate(), 10.0);
+
+        limiter.on_throttled();
+        assert_eq!(limiter.rate(), 5.0);
+
+        // A burst of throttled responses only backs off once
+        limiter.on_throttled();
+        limiter.on_throttled();
+        assert_eq!(limiter.rate(), 5.0);
+
+        for _ in 0..10_000 {
+            limiter.on_success();
+        }
+        assert_eq!(limiter.rate(), 10.0);
+    }
+
+    #[test]
+    fn never_drops_below_floor() {
+        let limiter = AdaptiveRateLimiter::new("test", 4.0, 10.0);
+        limiter.on_throttled();
+        assert_eq!(limiter.rate(), 5.0);
+        limiter.state.lock().unwrap().last_decrease = None;
+        limiter.on_throttled();
+        assert_eq!(limiter.rate(), 4.0);
+    }
+}
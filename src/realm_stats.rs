@@ -0,0 +1,322 @@
+use futures::TryStreamExt;
+use mongodb::bson::doc;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::*;
+
+use crate::database::DetailedStatRecord;
+use crate::error::*;
+use crate::ships::ShipDb;
+
+/// How often the realm-wide stats are recomputed
+const AGGREGATION_INTERVAL_HOURS: i64 = 6;
+
+/// Only the latest stats are kept, in the document with this `_id`
+const LATEST_ID: &str = "latest";
+
+/// How many ships to list as the most played
+const MOST_PLAYED_SHIPS: usize = 20;
+
+/// Ships with fewer players than this are left out of the balance ranking
+pub const MIN_BALANCE_PLAYERS: u64 = 100;
+
+/// Upper bounds (exclusive) of the account size buckets, in battles
+const ACCOUNT_SIZE_BUCKETS: [u64; 8] = [10, 100, 500, 1_000, 2_000, 5_000, 10_000, 20_000];
+
+/// Battles played within one class, tier or nation
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Breakdown {
+    pub key: String,
+    pub battles: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShipPopulation {
+    pub ship_id: u64,
+    pub name: Option<String>,
+    pub tier: Option<u64>,
+    pub ship_type: Option<String>,
+    pub players: u64,
+    pub battles: u64,
+    /// Battle-weighted winrate across everyone who plays the ship
+    pub winrate: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccountSizeBucket {
+    /// e.g. "100-499"
+    pub label: String,
+    pub accounts: u64,
+}
+
+/// Statistics about everyone we've scraped
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RealmStats {
+    pub computed: chrono::DateTime<chrono::Utc>,
+    pub accounts: u64,
+    pub ships_tracked: u64,
+    pub player_ship_records: u64,
+    pub battles: u64,
+    pub battles_by_class: Vec<Breakdown>,
+    pub battles_by_tier: Vec<Breakdown>,
+    pub battles_by_nation: Vec<Breakdown>,
+    pub most_played: Vec<ShipPopulation>,
+    /// Ships with enough players, from the highest winrate to the lowest
+    pub ship_balance: Vec<ShipPopulation>,
+    pub account_sizes: Vec<AccountSizeBucket>,
+}
+
+#[derive(Default)]
+struct ShipTotals {
+    players: u64,
+    battles: u64,
+    wins: u64,
+}
+
+/// Accumulates the stats one record at a time, so the collection can be streamed through
+#[derive(Default)]
+pub struct RealmStatsBuilder {
+    ships: HashMap<u64, ShipTotals>,
+    /// Total battles per account
+    accounts: HashMap<u64, u64>,
+    records: u64,
+}
+
+fn breakdown<K: ToString>(totals: BTreeMap<K, u64>) -> Vec<Breakdown> {
+    totals
+        .into_iter()
+        .map(|(key, battles)| Breakdown {
+            key: key.to_string(),
+            battles,
+        })
+        .collect()
+}
+
+impl RealmStatsBuilder {
+    pub fn add(&mut self, record: &DetailedStatRecord) {
+        self.records += 1;
+        let battles = record.pvp.battles as u64;
+        *self.accounts.entry(record.account_id).or_default() += battles;
+        if battles == 0 {
+            return;
+        }
+        let ship = self.ships.entry(record.ship_id).or_default();
+        ship.players += 1;
+        ship.battles += battles;
+        ship.wins += record.pvp.wins as u64;
+    }
+
+    pub fn finish(self, shipdb: &ShipDb) -> RealmStats {
+        let mut by_class: BTreeMap<String, u64> = BTreeMap::new();
+        let mut by_tier: BTreeMap<u64, u64> = BTreeMap::new();
+        let mut by_nation: BTreeMap<String, u64> = BTreeMap::new();
+        let mut ships: Vec<ShipPopulation> = self
+            .ships
+            .iter()
+            .map(|(ship_id, totals)| {
+                let info = shipdb.get_ship_info(*ship_id);
+                if let Some(info) = &info {
+                    *by_class.entry(info.ship_type.clone()).or_default() += totals.battles;
+                    *by_tier.entry(info.tier).or_default() += totals.battles;
+                    *by_nation.entry(info.nation.clone()).or_default() += totals.battles;
+                }
+                ShipPopulation {
+                    ship_id: *ship_id,
+                    name: info.as_ref().map(|x| x.name.clone()),
+                    tier: info.as_ref().map(|x| x.tier),
+                    ship_type: info.map(|x| x.ship_type),
+                    players: totals.players,
+                    battles: totals.battles,
+                    winrate: totals.wins as f64 / totals.battles as f64,
+                }
+            })
+            .collect();
+
+        ships.sort_by_key(|ship| std::cmp::Reverse(ship.battles));
+        let most_played = ships.iter().take(MOST_PLAYED_SHIPS).cloned().collect();
+
+        let mut ship_balance: Vec<ShipPopulation> = ships
+            .iter()
+            .filter(|ship| ship.players >= MIN_BALANCE_PLAYERS)
+            .cloned()
+            .collect();
+        ship_balance.sort_by(|a, b| {
+            b.winrate
+                .partial_cmp(&a.winrate)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let mut account_sizes: Vec<AccountSizeBucket> = ACCOUNT_SIZE_BUCKETS
+            .iter()
+            .enumerate()
+            .map(|(i, upper)| {
+                let lower = if i == 0 {
+                    0
+                } else {
+                    ACCOUNT_SIZE_BUCKETS[i - 1]
+                };
+                AccountSizeBucket {
+                    label: format!("{}-{}", lower, upper - 1),
+                    accounts: 0,
+                }
+            })
+            .collect();
+        account_sizes.push(AccountSizeBucket {
+            label: format!("{}+", ACCOUNT_SIZE_BUCKETS[ACCOUNT_SIZE_BUCKETS.len() - 1]),
+            accounts: 0,
+        });
+        for battles in self.accounts.values() {
+            let bucket = ACCOUNT_SIZE_BUCKETS
+                .iter()
+                .position(|upper| battles < upper)
+                .unwrap_or(ACCOUNT_SIZE_BUCKETS.len());
+            account_sizes[bucket].accounts += 1;
+        }
+
+        RealmStats {
+            computed: chrono::Utc::now(),
+            accounts: self.accounts.len() as u64,
+            ships_tracked: self.ships.len() as u64,
+            player_ship_records: self.records,
+            battles: self.ships.values().map(|x| x.battles).sum(),
+            battles_by_class: breakdown(by_class),
+            battles_by_tier: breakdown(by_tier),
+            battles_by_nation: breakdown(by_nation),
+            most_played,
+            ship_balance,
+            account_sizes,
+        }
+    }
+}
+
+/// Streams the whole playerstats collection through a `RealmStatsBuilder`
+async fn compute(
+    database: &mongodb::Database,
+    shipdb: &ShipDb,
+) -> Result<RealmStats, mongodb::error::Error> {
+    let collection = database.collection::<DetailedStatRecord>("playerstats");
+    let mut cursor = collection.find(None, None).await?;
+    let mut builder = RealmStatsBuilder::default();
+    while let Some(record) = cursor.try_next().await? {
+        builder.add(&record);
+    }
+    Ok(builder.finish(shipdb))
+}
+
+/// The most recently computed realm stats, shared with the web server
+#[derive(Clone)]
+pub struct RealmStatsDb {
+    latest: Arc<Mutex<Option<RealmStats>>>,
+}
+
+impl RealmStatsDb {
+    pub fn new() -> Self {
+        Self {
+            latest: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn get(&self) -> Option<RealmStats> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Picks up the last stats we saved, then recomputes them every few hours
+    pub async fn update_loop(self, database: mongodb::Database, shipdb: ShipDb) {
+        let collection = database.collection::<RealmStats>("realmstats");
+        if let Some(stats) = collection
+            .find_one(doc! { "_id": LATEST_ID }, None)
+            .await
+            .log_and_drop_error(|e| error!("Couldn't load realm stats, error {:?}", e))
+            .flatten()
+        {
+            *self.latest.lock().unwrap() = Some(stats);
+        }
+
+        loop {
+            let due = match self.get() {
+                Some(stats) => stats.computed + chrono::Duration::hours(AGGREGATION_INTERVAL_HOURS),
+                None => chrono::Utc::now(),
+            };
+            if let Ok(wait) = due.signed_duration_since(chrono::Utc::now()).to_std() {
+                sleep(wait).await;
+            }
+
+            info!("Computing realm stats");
+            match compute(&database, &shipdb).await {
+                Ok(stats) => {
+                    info!(
+                        "Realm has {} accounts over {} ships",
+                        stats.accounts, stats.ships_tracked
+                    );
+                    let options = mongodb::options::ReplaceOptions::builder()
+                        .upsert(true)
+                        .build();
+                    collection
+                        .replace_one(doc! { "_id": LATEST_ID }, &stats, options)
+                        .await
+                        .log_and_drop_error(|e| {
+                            error!("Couldn't store realm stats, error {:?}", e)
+                        });
+                    *self.latest.lock().unwrap() = Some(stats);
+                }
+                Err(e) => {
+                    error!("Couldn't compute realm stats, error {:?}", e);
+                    sleep(Duration::from_secs(3600)).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wows_data::DetailedStats;
+
+    fn record(account_id: u64, ship_id: u64, battles: u32, wins: u32) -> DetailedStatRecord {
+        let mut pvp = DetailedStats::zeroed();
+        pvp.battles = battles;
+        pvp.wins = wins;
+        DetailedStatRecord {
+            pvp,
+            account_id,
+            ship_id,
+            battles: battles as u64,
+            retrieved: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn aggregates_accounts_and_ships() {
+        let mut builder = RealmStatsBuilder::default();
+        for account in 0..150 {
+            builder.add(&record(account, 1, 40, 20));
+            builder.add(&record(account, 2, 10, account as u32 % 10));
+        }
+        builder.add(&record(1_000, 3, 5_000, 2_600));
+        let stats = builder.finish(&ShipDb::new("/nonexistent"));
+
+        assert_eq!(stats.accounts, 151);
+        assert_eq!(stats.ships_tracked, 3);
+        assert_eq!(stats.battles, 150 * 50 + 5_000);
+        assert_eq!(stats.most_played[0].ship_id, 1);
+
+        // Ship 3 has the best winrate, but only one player
+        let balance: Vec<u64> = stats.ship_balance.iter().map(|x| x.ship_id).collect();
+        assert_eq!(balance, vec![1, 2]);
+        assert!((stats.ship_balance[1].winrate - 0.45).abs() < 1e-9);
+
+        assert_eq!(stats.account_sizes[1].label, "10-99");
+        assert_eq!(stats.account_sizes[1].accounts, 150);
+        assert_eq!(stats.account_sizes[5].label, "2000-4999");
+        assert_eq!(stats.account_sizes[6].accounts, 1);
+        assert_eq!(stats.account_sizes[8].label, "20000+");
+
+        // Stored in Mongo, so the page is there straight after a restart
+        let stored = mongodb::bson::to_document(&stats).unwrap();
+        let loaded: RealmStats = mongodb::bson::from_document(stored).unwrap();
+        assert_eq!(loaded.ship_balance, stats.ship_balance);
+    }
+}
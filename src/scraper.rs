@@ -1,117 +1,292 @@
-use futures::future::FutureExt;
+use serde_derive::Serialize;
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
-use stream_throttle::{ThrottlePool, ThrottleRate};
 use tokio::sync::Semaphore;
 use tracing::*;
 
 use crate::error::Error;
 use crate::progress_logger::ProgressLogger;
+use crate::rate_limiter::AdaptiveRateLimiter;
 use crate::wows_data::*;
 
 const MAX_INFLIGHT_REQUESTS: usize = 30;
+const MAX_INFLIGHT_INTERACTIVE_REQUESTS: usize = 5;
 
-pub struct WowsClient {
+/// How many times a request is retried when the API tells us to slow down
+const MAX_THROTTLED_RETRIES: usize = 5;
+
+/// Request rates (in requests/sec) for the two kinds of traffic we send to the API. Background
+/// traffic (the poller and ship DB) and interactive traffic (lookups triggered by page loads) are
+/// throttled separately, so that a sweep can't starve a page load. These apply to each API key.
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    pub floor: f64,
+    pub background_ceiling: f64,
+    pub interactive_ceiling: f64,
+}
+
+/// A rate limiter plus a cap on the number of concurrent requests
+struct RequestBudget {
+    limiter: AdaptiveRateLimiter,
+    inflight_requests: Semaphore,
+    max_inflight_requests: usize,
+}
+
+impl RequestBudget {
+    fn new(label: &str, floor: f64, ceiling: f64, max_inflight_requests: usize) -> Self {
+        Self {
+            limiter: AdaptiveRateLimiter::new(label, floor, ceiling),
+            inflight_requests: Semaphore::new(max_inflight_requests),
+            max_inflight_requests,
+        }
+    }
+}
+
+/// The WoWS API reports these when we're sending requests faster than it would like
+fn is_throttle_error(error: &GenericReplyError) -> bool {
+    error.message == "REQUEST_LIMIT_EXCEEDED" || error.code == 504
+}
+
+/// The API reports this when it doesn't recognize the key at all, so retrying with it is pointless
+fn is_rejected_key_error(error: &GenericReplyError) -> bool {
+    error.message == "INVALID_APPLICATION_ID"
+}
+
+/// Only show the tail end of an API key, so it doesn't end up in logs or on a webpage
+fn redact_key(application_id: &str) -> String {
+    let tail: String = application_id
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("...{}", tail)
+}
+
+/// A single application_id, each of which the API throttles independently
+struct ApiKey {
     application_id: String,
+    background: RequestBudget,
+    interactive: RequestBudget,
+    healthy: AtomicBool,
+    requests: AtomicU64,
+    throttled: AtomicU64,
+}
+
+impl ApiKey {
+    fn new(application_id: &str, limits: &RateLimits) -> Self {
+        let redacted = redact_key(application_id);
+        Self {
+            application_id: application_id.to_string(),
+            background: RequestBudget::new(
+                &format!("background {}", redacted),
+                limits.floor,
+                limits.background_ceiling,
+                MAX_INFLIGHT_REQUESTS,
+            ),
+            interactive: RequestBudget::new(
+                &format!("interactive {}", redacted),
+                limits.floor,
+                limits.interactive_ceiling,
+                MAX_INFLIGHT_INTERACTIVE_REQUESTS,
+            ),
+            healthy: AtomicBool::new(true),
+            requests: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes the key out of rotation for good
+    fn mark_rejected(&self) {
+        error!(
+            "API key {} was rejected, no longer using it",
+            redact_key(&self.application_id)
+        );
+        self.healthy.store(false, Ordering::Relaxed);
+    }
+
+    fn budget(&self, interactive: bool) -> &RequestBudget {
+        if interactive {
+            &self.interactive
+        } else {
+            &self.background
+        }
+    }
+}
+
+/// How much a single API key has been used, for reporting
+#[derive(Debug, Serialize)]
+pub struct ApiKeyUsage {
+    pub key: String,
+    pub healthy: bool,
+    pub requests: u64,
+    pub throttled: u64,
+    pub background_rate: f64,
+    pub interactive_rate: f64,
+}
+
+pub struct WowsClient {
     client: reqwest::Client,
-    throttle_pool: ThrottlePool,
     logger: Arc<Mutex<ProgressLogger>>,
-    inflight_requests: Arc<Semaphore>,
+    keys: Arc<Vec<ApiKey>>,
+    interactive: bool,
 }
 
 impl WowsClient {
-    pub fn new(application_id: &str, request_period: u64) -> WowsClient {
+    pub fn new(application_ids: &[String], limits: &RateLimits) -> WowsClient {
         let client = reqwest::Client::new();
         WowsClient {
-            application_id: application_id.to_string(),
-            client: client,
-            throttle_pool: ThrottlePool::new(ThrottleRate::new(
-                1,
-                std::time::Duration::new(0, request_period.try_into().unwrap()),
-            )),
+            client,
             logger: Arc::new(Mutex::new(ProgressLogger::new("api_requests"))),
-            inflight_requests: Arc::new(Semaphore::new(MAX_INFLIGHT_REQUESTS)),
+            keys: Arc::new(
+                application_ids
+                    .iter()
+                    .map(|application_id| ApiKey::new(application_id, limits))
+                    .collect(),
+            ),
+            interactive: false,
         }
     }
 
     pub fn fork(&self) -> WowsClient {
         WowsClient {
-            application_id: self.application_id.to_string(),
             client: self.client.clone(),
-            throttle_pool: self.throttle_pool.clone(),
             logger: self.logger.clone(),
-            inflight_requests: self.inflight_requests.clone(),
+            keys: self.keys.clone(),
+            interactive: self.interactive,
         }
     }
 
+    /// Like `fork`, but requests made through the returned client draw from the interactive budget
+    pub fn fork_interactive(&self) -> WowsClient {
+        WowsClient {
+            interactive: true,
+            ..self.fork()
+        }
+    }
+
+    pub fn key_usage(&self) -> Vec<ApiKeyUsage> {
+        self.keys
+            .iter()
+            .map(|key| ApiKeyUsage {
+                key: redact_key(&key.application_id),
+                healthy: key.healthy.load(Ordering::Relaxed),
+                requests: key.requests.load(Ordering::Relaxed),
+                throttled: key.throttled.load(Ordering::Relaxed),
+                background_rate: key.background.limiter.rate(),
+                interactive_rate: key.interactive.limiter.rate(),
+            })
+            .collect()
+    }
+
+    /// Picks the healthy key which will be able to send a request the soonest
+    fn pick_key(&self) -> Option<&ApiKey> {
+        self.keys
+            .iter()
+            .filter(|key| key.healthy.load(Ordering::Relaxed))
+            .min_by_key(|key| key.budget(self.interactive).limiter.next_slot())
+    }
+
     async fn request<T: serde::de::DeserializeOwned>(
         &self,
         uri: &str,
         params: &[(&str, &str)],
-    ) -> Result<T, Error> {
-        let mut params = params.to_vec();
-        params.push(("application_id", self.application_id.as_str()));
-
-        let body = self
-            .throttle_pool
-            .queue()
-            .then(|_| async {
-                let _permit = self.inflight_requests.acquire().await.unwrap();
-                self.client
-                    .get(uri)
-                    .form(&params)
-                    .send()
-                    .await?
-                    .text()
-                    .await
-            })
-            .await
-            .map_err(|e| Error::Http {
-                err: e,
+    ) -> Result<GenericReply<T>, Error> {
+        let error_params = |params: &[(&str, &str)]| {
+            params
+                .iter()
+                .map(|(a, b)| (a.to_string(), b.to_string()))
+                .collect()
+        };
+
+        let mut attempt = 0;
+        loop {
+            let key = self.pick_key().ok_or_else(|| Error::NoHealthyApiKeys {
                 url: uri.to_string(),
-                params: params
-                    .iter()
-                    .map(|(a, b)| (a.to_string(), b.to_string()))
-                    .collect(),
+                params: error_params(params),
             })?;
-        match serde_json::from_str(&body) {
-            Ok(x) => {
-                {
-                    let mut logger = self.logger.lock().unwrap();
-                    if logger.increment(1) {
-                        debug!(
-                            "Currently {} WoWS API requests in flight",
-                            MAX_INFLIGHT_REQUESTS - self.inflight_requests.available_permits()
-                        );
-                    }
-                }
-                Ok(x)
+            let budget = key.budget(self.interactive);
+            let mut params = params.to_vec();
+            params.push(("application_id", key.application_id.as_str()));
+
+            attempt += 1;
+            budget.limiter.acquire().await;
+            let (status, body) = {
+                let _permit = budget.inflight_requests.acquire().await.unwrap();
+                let response = async {
+                    let response = self.client.get(uri).form(&params).send().await?;
+                    let status = response.status();
+                    Ok((status, response.text().await?))
+                };
+                response.await.map_err(|e| Error::Http {
+                    err: e,
+                    url: uri.to_string(),
+                    params: error_params(&params),
+                })?
+            };
+            key.requests.fetch_add(1, Ordering::Relaxed);
+
+            let throttled = status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+                || status == reqwest::StatusCode::GATEWAY_TIMEOUT;
+            if throttled && attempt < MAX_THROTTLED_RETRIES {
+                key.throttled.fetch_add(1, Ordering::Relaxed);
+                budget.limiter.on_throttled();
+                continue;
             }
-            Err(e) => {
-                // It's possible we may need to parse it as an error
-                /*
-                {"status":"error","error":{"code":504,"message":"SOURCE_NOT_AVAILABLE","field":null,"value":null}}
-                */
-                // and then retry or return that error or something
-                Err(Error::HttpParse {
+
+            let reply: GenericReply<T> =
+                serde_json::from_str(&body).map_err(|e| Error::HttpParse {
                     err: e,
                     url: uri.to_string(),
-                    params: params
-                        .iter()
-                        .map(|(a, b)| (a.to_string(), b.to_string()))
-                        .collect(),
-                })
+                    params: error_params(&params),
+                })?;
+
+            match reply.error.as_ref() {
+                Some(error) if is_rejected_key_error(error) => {
+                    // Retry with a different key, if we have one
+                    key.mark_rejected();
+                    attempt -= 1;
+                    continue;
+                }
+                Some(error) if is_throttle_error(error) => {
+                    key.throttled.fetch_add(1, Ordering::Relaxed);
+                    budget.limiter.on_throttled();
+                    if attempt < MAX_THROTTLED_RETRIES {
+                        continue;
+                    }
+                }
+                _ => {
+                    budget.limiter.on_success();
+                }
             }
+
+            {
+                let mut logger = self.logger.lock().unwrap();
+                if logger.increment(1) {
+                    debug!(
+                        "Currently {} WoWS API requests in flight on key {}, {:.2} requests/sec allowed",
+                        budget.max_inflight_requests - budget.inflight_requests.available_permits(),
+                        redact_key(&key.application_id),
+                        budget.limiter.rate()
+                    );
+                }
+            }
+            return Ok(reply);
         }
     }
 
-    async fn list_players_helper(&self, search: &str) -> Result<Vec<PlayerRecord>, Error> {
+    async fn list_players_helper(
+        &self,
+        params: &[(&str, &str)],
+    ) -> Result<Vec<PlayerRecord>, Error> {
         let uri = "https://api.worldofwarships.com/wows/account/list/";
-        let params = [("search", search)];
         loop {
-            let reply: GenericReply<Vec<PlayerRecord>> = self.request(uri, &params).await?;
+            let reply: GenericReply<Vec<PlayerRecord>> = self.request(uri, params).await?;
             if let Some(data) = reply.data {
                 return Ok(data);
             } else if reply
@@ -141,7 +316,9 @@ impl WowsClient {
         let mut i = 0;
         let mut result = vec![];
         while i < searches.len() {
-            let mut reply = self.list_players_helper(&searches[i]).await?;
+            let mut reply = self
+                .list_players_helper(&[("search", searches[i].as_str())])
+                .await?;
             if reply.len() == 100 {
                 // Gotta go re-request for each sub-uri
                 let chars = vec![
@@ -162,6 +339,14 @@ impl WowsClient {
         Ok(result)
     }
 
+    /// Looks up a single player by their exact nickname
+    pub async fn find_player(&self, nickname: &str) -> Result<Option<PlayerRecord>, Error> {
+        let players = self
+            .list_players_helper(&[("search", nickname), ("type", "exact")])
+            .await?;
+        Ok(players.into_iter().next())
+    }
+
     pub async fn get_detailed_stats(
         &self,
         account_id: u64,
@@ -180,8 +365,25 @@ impl WowsClient {
                     .map(|(a, b)| (a.to_string(), b.to_string()))
                     .collect(),
                 status: reply.status,
-                meta: reply.meta,
-                error: reply.error,
+                meta: reply.meta.map(Box::new),
+                error: reply.error.map(Box::new),
+            }),
+        }
+    }
+
+    pub async fn get_encyclopedia_info(&self) -> Result<EncyclopediaInfo, Error> {
+        let uri = "https://api.worldofwarships.com/wows/encyclopedia/info/";
+        let params = [("fields", "game_version")];
+        let reply: GenericReply<EncyclopediaInfo> = self.request(uri, &params[..]).await?;
+        match reply.data {
+            Some(data) => Ok(data),
+            None => Err(Error::ApiError {
+                err: reply.error.map(|e| e.code).unwrap_or(0),
+                url: uri.to_string(),
+                params: params
+                    .iter()
+                    .map(|(a, b)| (a.to_string(), b.to_string()))
+                    .collect(),
             }),
         }
     }
@@ -214,11 +416,8 @@ impl WowsClient {
             .expect("Expected data for enumerate_ships")
             .iter()
         {
-            match v {
-                Some(v) => {
-                    result.insert(k.parse().unwrap(), v.clone());
-                }
-                None => {}
+            if let Some(v) = v {
+                result.insert(k.parse().unwrap(), v.clone());
             }
         }
         for page in 2..reply
@@ -233,14 +432,72 @@ impl WowsClient {
             let reply: GenericReply<HashMap<String, Option<ShipInfo>>> =
                 self.request(uri, &params[..]).await?;
             for (k, v) in reply.data.expect("Expected data for reply data").iter() {
-                match v {
-                    Some(v) => {
-                        result.insert(k.parse().unwrap(), v.clone());
-                    }
-                    None => {}
+                if let Some(v) = v {
+                    result.insert(k.parse().unwrap(), v.clone());
                 }
             }
         }
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(rate: f64) -> RateLimits {
+        RateLimits {
+            floor: rate,
+            background_ceiling: rate,
+            interactive_ceiling: rate,
+        }
+    }
+
+    fn keys(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("key{}", i)).collect()
+    }
+
+    #[test]
+    fn requests_are_spread_across_keys() {
+        let client = WowsClient::new(&keys(3), &limits(1_000.0));
+        let mut picks: HashMap<String, usize> = HashMap::new();
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            for _ in 0..300 {
+                let key = client.pick_key().unwrap();
+                key.background.limiter.acquire().await;
+                *picks.entry(key.application_id.clone()).or_default() += 1;
+            }
+        });
+        // Each acquire pushes that key's next slot back, so the next pick goes to another key
+        assert_eq!(picks.len(), 3);
+        for count in picks.values() {
+            assert!((99..=101).contains(count), "{:?}", picks);
+        }
+    }
+
+    #[test]
+    fn rejected_keys_are_taken_out_of_rotation() {
+        let client = WowsClient::new(&keys(2), &limits(1.0));
+        let rejected: GenericReplyError = serde_json::from_str(
+            r#"{"code": 407, "message": "INVALID_APPLICATION_ID", "field": "application_id"}"#,
+        )
+        .unwrap();
+        assert!(is_rejected_key_error(&rejected));
+
+        // key1 would be picked next, since key0 has already used up its slot
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(client.keys[0].background.limiter.acquire());
+        let key = client.pick_key().unwrap();
+        assert_eq!(key.application_id, "key1");
+        key.mark_rejected();
+
+        // key0 has to wait its turn, but it's the only one left
+        for _ in 0..3 {
+            assert_eq!(client.pick_key().unwrap().application_id, "key0");
+        }
+        assert!(!client.key_usage()[1].healthy);
+        client.keys[0].mark_rejected();
+        assert!(client.pick_key().is_none());
+    }
+}
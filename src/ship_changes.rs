@@ -0,0 +1,278 @@
+use futures::TryStreamExt;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::*;
+
+use crate::error::*;
+use crate::ships::ShipsDiff;
+use crate::wows_data::{DetailedModuleInfo, ShipInfo};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShipChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A single change to a ship between two refreshes of the encyclopedia. Modifications are recorded
+/// per-field, with `field`, `old` and `new` describing what changed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShipChange {
+    pub ship_id: u64,
+    pub name: String,
+    pub kind: ShipChangeKind,
+    pub field: Option<String>,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    pub game_version: Option<String>,
+    pub detected: chrono::DateTime<chrono::Utc>,
+}
+
+/// The fields we keep track of, as (name, value) pairs
+fn tracked_fields(
+    ship: &ShipInfo,
+    modules: &HashMap<u64, DetailedModuleInfo>,
+) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        (
+            "speed",
+            ship.default_profile
+                .mobility
+                .as_ref()
+                .map(|x| format!("{}", x.max_speed)),
+        ),
+        (
+            "torpedo_range",
+            ship.torpedo_range(modules).map(|x| format!("{}", x)),
+        ),
+        (
+            "battle_tiers",
+            match (
+                ship.default_profile.battle_level_range_min,
+                ship.default_profile.battle_level_range_max,
+            ) {
+                (Some(min), Some(max)) => Some(format!("{}-{}", min, max)),
+                _ => None,
+            },
+        ),
+        ("price_credit", Some(format!("{}", ship.price_credit))),
+        ("price_gold", Some(format!("{}", ship.price_gold))),
+    ]
+}
+
+/// Works out what changed about each ship. Every ship in both versions is compared, not just the
+/// ones in `diff.changed`, since a patch may only change a module (say, a torpedo's range).
+pub fn changes_between(
+    diff: &ShipsDiff,
+    old: (&HashMap<u64, ShipInfo>, &HashMap<u64, DetailedModuleInfo>),
+    new: (&HashMap<u64, ShipInfo>, &HashMap<u64, DetailedModuleInfo>),
+    game_version: &Option<String>,
+) -> Vec<ShipChange> {
+    let (old_ships, old_modules) = old;
+    let (new_ships, new_modules) = new;
+    let detected = chrono::Utc::now();
+    let change = |ship: &ShipInfo, kind| ShipChange {
+        ship_id: ship.ship_id,
+        name: ship.name.clone(),
+        kind,
+        field: None,
+        old: None,
+        new: None,
+        game_version: game_version.clone(),
+        detected,
+    };
+
+    let mut changes = vec![];
+    for ship in diff.added.iter().filter_map(|id| new_ships.get(id)) {
+        changes.push(change(ship, ShipChangeKind::Added));
+    }
+    for ship in diff.removed.iter().filter_map(|id| old_ships.get(id)) {
+        changes.push(change(ship, ShipChangeKind::Removed));
+    }
+    let mut kept: Vec<u64> = new_ships
+        .keys()
+        .filter(|id| old_ships.contains_key(id))
+        .copied()
+        .collect();
+    kept.sort_unstable();
+    for id in kept.iter() {
+        let (old_ship, new_ship) = (&old_ships[id], &new_ships[id]);
+        let old_fields = tracked_fields(old_ship, old_modules);
+        let new_fields = tracked_fields(new_ship, new_modules);
+        for ((field, old_value), (_, new_value)) in old_fields.into_iter().zip(new_fields) {
+            if old_value != new_value {
+                changes.push(ShipChange {
+                    field: Some(field.to_string()),
+                    old: old_value,
+                    new: new_value,
+                    ..change(new_ship, ShipChangeKind::Modified)
+                });
+            }
+        }
+    }
+    changes
+}
+
+pub async fn store_changes(database: &mongodb::Database, changes: &[ShipChange]) {
+    if changes.is_empty() {
+        return;
+    }
+    let collection = database.collection::<ShipChange>("shipchanges");
+    collection
+        .insert_many(changes, None)
+        .await
+        .log_and_drop_error(|e| {
+            error!(
+                "Couldn't store {} ship changes, error {:?}",
+                changes.len(),
+                e
+            );
+        });
+}
+
+/// All of the changes we've seen, newest first
+pub async fn load_changes(database: &mongodb::Database) -> Vec<ShipChange> {
+    let collection = database.collection::<ShipChange>("shipchanges");
+    let options = mongodb::options::FindOptions::builder()
+        .sort(mongodb::bson::doc! { "detected": -1 })
+        .build();
+    let cursor = match collection.find(None, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            error!("Couldn't load ship changes, error {:?}", e);
+            return vec![];
+        }
+    };
+    cursor.try_collect().await.unwrap_or_else(|e| {
+        error!("Couldn't load ship changes, error {:?}", e);
+        vec![]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ship(id: u64, max_speed: f32, price_credit: u64) -> ShipInfo {
+        serde_json::from_value(serde_json::json!({
+            "description": "",
+            "price_gold": 0,
+            "ship_id_str": "",
+            "has_demo_profile": false,
+            "images": {},
+            "modules": {},
+            "modules_tree": {
+                "100": {
+                    "name": "Torpedoes",
+                    "is_default": true,
+                    "price_xp": 0,
+                    "price_credit": 0,
+                    "next_ships": null,
+                    "next_modules": null,
+                    "module_id": 100,
+                    "type": "Torpedoes",
+                    "module_id_str": "",
+                },
+            },
+            "nation": "usa",
+            "is_premium": false,
+            "ship_id": id,
+            "price_credit": price_credit,
+            "default_profile": {
+                "mobility": {
+                    "rudder_time": 1.0,
+                    "total": 1.0,
+                    "turning_radius": 1.0,
+                    "max_speed": max_speed,
+                },
+                "torpedoes": null,
+                "battle_level_range_max": 7,
+                "battle_level_range_min": 5,
+            },
+            "upgrades": null,
+            "tier": 5,
+            "next_ships": {},
+            "mod_slots": 2,
+            "type": "Cruiser",
+            "is_special": false,
+            "name": format!("Ship {}", id),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn records_added_removed_and_modified_fields() {
+        let modules = HashMap::new();
+        let mut old = HashMap::new();
+        old.insert(1, ship(1, 30.0, 1000));
+        old.insert(2, ship(2, 30.0, 1000));
+        let mut new = HashMap::new();
+        new.insert(1, ship(1, 32.5, 1000));
+        new.insert(3, ship(3, 30.0, 1000));
+
+        let diff = ShipsDiff::between(&old, &new);
+        let changes = changes_between(&diff, (&old, &modules), (&new, &modules), &None);
+
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| {
+                (
+                    c.ship_id,
+                    c.kind,
+                    c.field.clone(),
+                    c.old.clone(),
+                    c.new.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (3, ShipChangeKind::Added, None, None, None),
+                (2, ShipChangeKind::Removed, None, None, None),
+                (
+                    1,
+                    ShipChangeKind::Modified,
+                    Some("speed".to_string()),
+                    Some("30".to_string()),
+                    Some("32.5".to_string())
+                ),
+            ]
+        );
+    }
+
+    fn torpedoes(distance: f32) -> HashMap<u64, DetailedModuleInfo> {
+        let module = serde_json::from_value(serde_json::json!({
+            "module_id": 100,
+            "type": "Torpedoes",
+            "profile": {
+                "torpedoes": {
+                    "torpedo_speed": 60,
+                    "shot_speed": 60.0,
+                    "max_damage": 10000,
+                    "distance": distance,
+                },
+            },
+        }))
+        .unwrap();
+        let mut modules = HashMap::new();
+        modules.insert(100, module);
+        modules
+    }
+
+    #[test]
+    fn records_module_only_changes() {
+        let mut ships = HashMap::new();
+        ships.insert(1, ship(1, 30.0, 1000));
+        let (old_modules, new_modules) = (torpedoes(8.0), torpedoes(10.0));
+
+        let diff = ShipsDiff::between(&ships, &ships);
+        assert!(diff.changed.is_empty());
+        let changes = changes_between(&diff, (&ships, &old_modules), (&ships, &new_modules), &None);
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field.as_deref(), Some("torpedo_range"));
+        assert_eq!(changes[0].old.as_deref(), Some("8"));
+        assert_eq!(changes[0].new.as_deref(), Some("10"));
+    }
+}
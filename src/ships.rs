@@ -1,23 +1,126 @@
-use itertools::Itertools;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use tokio::time::{sleep, Duration};
 use tracing::*;
 
+use crate::error::Error;
 use crate::scraper::WowsClient;
 use crate::wows_data::{DetailedModuleInfo, ShipInfo};
 
+/// How long a cached encyclopedia is trusted for, if the game version hasn't changed. This is only
+/// a backstop in case the encyclopedia changes without a new version, so it's much longer than
+/// `VERSION_POLL_INTERVAL`.
+const CACHE_MAX_AGE_DAYS: i64 = 14;
+
+/// How often we check whether the game version has changed
+const VERSION_POLL_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A copy of the encyclopedia as of some game version, so that we have ship data as soon as we
+/// start up instead of waiting for the whole thing to download again.
+#[derive(Serialize, Deserialize)]
+struct EncyclopediaCache {
+    game_version: Option<String>,
+    retrieved: chrono::DateTime<chrono::Utc>,
+    ships: HashMap<u64, ShipInfo>,
+    modules: HashMap<u64, DetailedModuleInfo>,
+}
+
+/// Which ships were added, removed or changed between two versions of the encyclopedia
+#[derive(Debug, Default, PartialEq)]
+pub struct ShipsDiff {
+    pub added: Vec<u64>,
+    pub removed: Vec<u64>,
+    pub changed: Vec<u64>,
+}
+
+impl ShipsDiff {
+    pub fn between(old: &HashMap<u64, ShipInfo>, new: &HashMap<u64, ShipInfo>) -> Self {
+        let mut diff = ShipsDiff::default();
+        for (id, ship) in new.iter() {
+            match old.get(id) {
+                Some(old_ship) if old_ship != ship => diff.changed.push(*id),
+                Some(_) => {}
+                None => diff.added.push(*id),
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|id| !new.contains_key(id))
+            .copied()
+            .collect();
+        diff.added.sort_unstable();
+        diff.removed.sort_unstable();
+        diff.changed.sort_unstable();
+        diff
+    }
+
+    fn log(&self, old: &HashMap<u64, ShipInfo>, new: &HashMap<u64, ShipInfo>) {
+        let name = |id: &u64| {
+            new.get(id)
+                .or_else(|| old.get(id))
+                .map(|ship| ship.name.clone())
+                .unwrap_or_default()
+        };
+        info!(
+            "Ship DB refresh: {} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        );
+        for id in self.added.iter() {
+            info!("Ship added: {} ({})", name(id), id);
+        }
+        for id in self.removed.iter() {
+            info!("Ship removed: {} ({})", name(id), id);
+        }
+        for id in self.changed.iter() {
+            info!("Ship changed: {} ({})", name(id), id);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ShipDb {
     ships: Arc<Mutex<HashMap<u64, ShipInfo>>>,
     modules: Arc<Mutex<HashMap<u64, DetailedModuleInfo>>>,
+    game_version: Arc<Mutex<Option<String>>>,
+    retrieved: Arc<Mutex<Option<chrono::DateTime<chrono::Utc>>>>,
+    cache_path: String,
 }
 
 impl ShipDb {
-    pub fn new() -> Self {
-        let ships = Arc::new(Mutex::new(HashMap::new()));
-        let modules = Arc::new(Mutex::new(HashMap::new()));
-        Self { ships, modules }
+    /// Creates a ship DB, pre-populated from the cache at `cache_path` if there is one.
+    pub fn new(cache_path: &str) -> Self {
+        let cache = std::fs::read(cache_path)
+            .and_then(|data| Ok(serde_json::from_slice::<EncyclopediaCache>(&data)?));
+        let (ships, modules, game_version, retrieved) = match cache {
+            Ok(cache) => {
+                info!(
+                    "Loaded {} ships and {} modules from cache (game version {:?})",
+                    cache.ships.len(),
+                    cache.modules.len(),
+                    cache.game_version
+                );
+                (
+                    cache.ships,
+                    cache.modules,
+                    cache.game_version,
+                    Some(cache.retrieved),
+                )
+            }
+            Err(e) => {
+                info!("Not using ship DB cache {}: {:?}", cache_path, e);
+                (HashMap::new(), HashMap::new(), None, None)
+            }
+        };
+        Self {
+            ships: Arc::new(Mutex::new(ships)),
+            modules: Arc::new(Mutex::new(modules)),
+            game_version: Arc::new(Mutex::new(game_version)),
+            retrieved: Arc::new(Mutex::new(retrieved)),
+            cache_path: cache_path.to_string(),
+        }
     }
 
     pub fn get_ship_info(&self, shipid: u64) -> Option<ShipInfo> {
@@ -35,70 +138,155 @@ impl ShipDb {
         modules.clone()
     }
 
-    async fn update_modules(&mut self, client: &WowsClient) {
-        let new_modules = {
-            let moduleids = {
-                let ships = self.ships.lock().unwrap();
+    async fn download_modules(
+        ships: &HashMap<u64, ShipInfo>,
+        client: &WowsClient,
+    ) -> Result<HashMap<u64, DetailedModuleInfo>, Error> {
+        let mut moduleids = HashSet::new();
+        for ship in ships.values() {
+            for module in ship.modules_tree.values() {
+                moduleids.insert(module.module_id);
+            }
+        }
+
+        info!("Downloading {} modules...", moduleids.len());
 
-                let mut moduleids = HashSet::new();
-                for (_, ship) in ships.iter() {
-                    for (_, module) in ship.modules_tree.iter() {
-                        moduleids.insert(module.module_id);
-                    }
-                }
-                moduleids
-            };
+        let moduleids: Vec<u64> = moduleids.into_iter().collect();
+        let mut modules = HashMap::new();
+        for chunk in moduleids.chunks(100) {
+            modules.extend(client.get_module_info(chunk).await?);
+        }
+        Ok(modules)
+    }
 
-            info!("Downloading {} modules...", moduleids.len());
+    /// Whether the data we have is recent and from the current version of the game
+    fn is_current(&self, game_version: &Option<String>) -> bool {
+        let retrieved = match *self.retrieved.lock().unwrap() {
+            Some(x) => x,
+            None => return false,
+        };
+        let age = chrono::Utc::now().signed_duration_since(retrieved);
+        game_version.is_some()
+            && *game_version == *self.game_version.lock().unwrap()
+            && age < chrono::Duration::days(CACHE_MAX_AGE_DAYS)
+    }
 
-            let mut queries = vec![];
-            for moduleids in &moduleids.iter().chunks(100) {
-                let chunk: Vec<_> = moduleids.map(|x| *x).collect();
-                queries.push(chunk);
+    async fn refresh(
+        &self,
+        client: &WowsClient,
+        database: &mongodb::Database,
+        game_version: Option<String>,
+    ) {
+        let ships = match client.enumerate_ships().await {
+            Ok(data) => {
+                info!("Loaded ship database, contains {} ships", data.len());
+                data
             }
+            Err(e) => {
+                error!("Error enumerating ships: {:?}", e);
+                return;
+            }
+        };
 
-            let mut modules = HashMap::new();
-            for chunk in queries.iter() {
-                for (id, module) in client
-                    .get_module_info(chunk.as_slice())
-                    .await
-                    .unwrap()
-                    .iter()
-                {
-                    modules.insert(*id, module.clone());
-                }
+        info!("Loading modules...");
+        let modules = match Self::download_modules(&ships, client).await {
+            Ok(modules) => modules,
+            Err(e) => {
+                error!("Error downloading modules: {:?}", e);
+                return;
             }
-            modules
         };
+        info!("Modules updated successfully");
 
-        let mut modules = self.modules.lock().unwrap();
-        *modules = new_modules;
-    }
+        // Keep track of what changed, unless this is the first time we've seen the ships at all
+        let changes = {
+            let old_ships = self.ships.lock().unwrap();
+            let old_modules = self.modules.lock().unwrap();
+            if old_ships.is_empty() {
+                vec![]
+            } else {
+                let diff = ShipsDiff::between(&old_ships, &ships);
+                diff.log(&old_ships, &ships);
+                crate::ship_changes::changes_between(
+                    &diff,
+                    (&old_ships, &old_modules),
+                    (&ships, &modules),
+                    &game_version,
+                )
+            }
+        };
+        crate::ship_changes::store_changes(database, &changes).await;
 
-    pub async fn update_loop(mut self, client: WowsClient) {
-        loop {
-            info!("Updating ship DB");
-            match client.enumerate_ships().await {
-                Ok(data) => {
-                    info!("Loaded ship database, contains {} ships", data.len());
-                    let mut ships = self.ships.lock().unwrap();
-                    *ships = data;
-                }
-                Err(e) => {
-                    error!("Error enumerating ships: {:?}", e);
+        let cache = EncyclopediaCache {
+            game_version,
+            retrieved: chrono::Utc::now(),
+            ships,
+            modules,
+        };
+        match serde_json::to_vec(&cache) {
+            Ok(data) => {
+                if let Err(e) = tokio::fs::write(&self.cache_path, data).await {
+                    error!("Couldn't write ship DB cache {}: {:?}", self.cache_path, e);
                 }
             }
+            Err(e) => {
+                error!("Couldn't serialize ship DB cache: {:?}", e);
+            }
+        }
+
+        *self.ships.lock().unwrap() = cache.ships;
+        *self.modules.lock().unwrap() = cache.modules;
+        *self.game_version.lock().unwrap() = cache.game_version;
+        *self.retrieved.lock().unwrap() = Some(cache.retrieved);
+    }
+
+    pub async fn update_loop(self, client: WowsClient, database: mongodb::Database) {
+        loop {
+            debug!("Checking whether the ship DB is up to date");
+            let game_version = client
+                .get_encyclopedia_info()
+                .await
+                .map(|info| info.game_version)
+                .map_err(|e| {
+                    error!("Error retrieving game version: {:?}", e);
+                })
+                .ok();
 
-            info!("Loading modules...");
-            self.update_modules(&client).await;
-            info!("Modules updated successfully");
+            if self.is_current(&game_version) {
+                debug!(
+                    "Ship DB is already up to date for game version {:?}",
+                    game_version
+                );
+            } else {
+                info!("Updating ship DB for game version {:?}", game_version);
+                self.refresh(&client, &database, game_version).await;
+            }
 
-            sleep(Duration::from_millis(24 * 3600 * 1000)).await;
+            sleep(VERSION_POLL_INTERVAL).await;
         }
     }
 
     pub fn enumerate_ships(&self) -> Vec<u64> {
         let ships = self.ships.lock().unwrap();
-        ships.iter().map(|(k, _)| *k).collect()
+        ships.keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_version_skips_the_refresh() {
+        let db = ShipDb::new("/nonexistent");
+        let version = Some("0.10.5".to_string());
+        assert!(!db.is_current(&version));
+
+        *db.game_version.lock().unwrap() = version.clone();
+        *db.retrieved.lock().unwrap() = Some(chrono::Utc::now() - chrono::Duration::days(3));
+        assert!(db.is_current(&version));
+        assert!(!db.is_current(&Some("0.10.6".to_string())));
+        // If we can't tell which version is live, refresh to be safe
+        assert!(!db.is_current(&None));
     }
 }
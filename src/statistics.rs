@@ -1,101 +1,840 @@
+use arc_swap::ArcSwap;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration};
+use tracing::*;
 
-use crate::histogram::RunningHistogram;
+use crate::database::DetailedStatRecord;
+use crate::histogram::{RunningHistogram, Snapshot, Window};
+use crate::metrics::MetricRegistry;
+use crate::ships::ShipDb;
 use crate::wows_data::*;
 
-fn initial_max_val(key: &str) -> f64 {
-    match key {
-        "main_battery.frags" => 20.0,
-        "main_battery.shots" => 10_000.0,
-        "main_battery.hits" => 1000.0,
-        "main_battery.hitrate" => 1.0,
-        "second_battery.frags" => 20.0,
-        "second_battery.shots" => 10_000.0,
-        "second_battery.hits" => 1000.0,
-        "second_battery.hitrate" => 1.0,
-        "torpedoes.frags" => 20.0,
-        "torpedoes.shots" => 10_000.0,
-        "torpedoes.hits" => 1000.0,
-        "torpedoes.hitrate" => 1.0,
-        "ramming.frags" => 20.0,
-        "ramming.shots" => 10_000.0,
-        "ramming.hits" => 1000.0,
-        "ramming.hitrate" => 1.0,
-        "aircraft.frags" => 20.0,
-        "aircraft.shots" => 10_000.0,
-        "aircraft.hits" => 1000.0,
-        "aircraft.hitrate" => 1.0,
-
-        "xp" => 10_000.0,
-        "capture_points" => 10.0,
-        "planes_killed" => 100.0,
-        "survived_wins" => 1.0,
-        "damage_scouting" => 1_000_000.0,
-        "damage_dealt" => 1_000_000.0,
-        "ships_spotted" => 100.0,
-        "frags" => 12.0,
-        "win_surviverate" => 1.0,
-        "winrate" => 1.0,
-
-        _ => 0.0,
+/// How many qualifying players a cohort needs before we trust its percentiles
+const MIN_COHORT_SAMPLES: u64 = 50;
+
+/// A group of ships whose players are compared against each other
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Cohort {
+    Ship(u64),
+    TierClass { tier: u64, class: String },
+    NationClass { nation: String, class: String },
+    Global,
+}
+
+impl Cohort {
+    /// Who the player is being compared against, e.g. "players on this ship"
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Ship(_) => "players on this ship".to_string(),
+            Self::TierClass { tier, class } => format!("tier {} {} players", tier, class),
+            Self::NationClass { nation, class } => format!("{} {} players", nation, class),
+            Self::Global => "all players".to_string(),
+        }
+    }
+
+    /// The cohorts a ship belongs to, from narrowest to broadest
+    fn all_for(ship_id: u64, info: Option<&ShipInfo>) -> Vec<Self> {
+        let mut cohorts = vec![Self::Ship(ship_id)];
+        if let Some(info) = info {
+            cohorts.push(Self::TierClass {
+                tier: info.tier,
+                class: info.ship_type.clone(),
+            });
+            cohorts.push(Self::NationClass {
+                nation: info.nation.clone(),
+                class: info.ship_type.clone(),
+            });
+        }
+        cohorts.push(Self::Global);
+        cohorts
+    }
+}
+
+#[derive(Default)]
+struct CohortHistograms {
+    stats: HashMap<String, RunningHistogram>,
+    /// How many updates have been made since the cohort was last published
+    unpublished: usize,
+}
+
+impl CohortHistograms {
+    fn histogram(
+        &mut self,
+        cohort: &Cohort,
+        key: &str,
+        metrics: &MetricRegistry,
+        window: Window,
+    ) -> &mut RunningHistogram {
+        if !self.stats.contains_key(key) {
+            let initial_max = metrics.get(key).map(|x| x.initial_max).unwrap_or(1.0);
+            let label = format!("{:?}-{}", cohort, key);
+            self.stats.insert(
+                key.to_owned(),
+                RunningHistogram::new(label, initial_max, window),
+            );
+        }
+        self.stats.get_mut(key).unwrap()
+    }
+
+    fn snapshot(&self, now: chrono::DateTime<chrono::Utc>, window: Window) -> CohortSnapshot {
+        let stats: HashMap<String, Snapshot> = self
+            .stats
+            .iter()
+            .map(|(k, h)| (k.to_owned(), h.snapshot(now)))
+            .collect();
+        CohortSnapshot {
+            epoch: window.epoch_of(now),
+            samples: stats.values().map(|x| x.len()).max().unwrap_or(0),
+            stats,
+        }
+    }
+}
+
+/// What page loads see of a cohort: its histograms as of the last time it was published
+struct CohortSnapshot {
+    /// The epoch the snapshot was weighed in, so it can be weighed again once that's over
+    epoch: i64,
+    /// How many qualifying players were counted
+    samples: u64,
+    stats: HashMap<String, Snapshot>,
+}
+
+impl Default for CohortSnapshot {
+    fn default() -> Self {
+        Self {
+            epoch: i64::MIN,
+            samples: 0,
+            stats: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct CohortEntry {
+    /// Only ever locked by writers
+    live: Mutex<CohortHistograms>,
+    /// Swapped out whole whenever the cohort is published, so readers never wait on writers
+    published: ArcSwap<CohortSnapshot>,
+}
+
+/// A player's percentiles, and which cohort they were compared against
+pub struct Percentiles {
+    pub cohort: Cohort,
+    pub values: HashMap<String, f64>,
+}
+
+/// How many shards the account map is split into
+const ACCOUNT_SHARDS: u64 = 64;
+
+/// How many updates a cohort takes before it's published again. Publishing goes over every bucket
+/// of every histogram in the cohort, so it isn't worth doing for every update.
+const PUBLISH_EVERY: usize = 1_000;
+
+/// How often cohorts with fewer updates than that are published anyway
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many records a priming worker works through before merging them into the histograms
+pub const PRIMING_BATCH_RECORDS: usize = 5_000;
+
+/// Values waiting to be added to a histogram, along with when they were retrieved
+type PendingValues = Vec<(f64, chrono::DateTime<chrono::Utc>)>;
+
+/// Accounts a priming worker has read from the database, but not yet counted.
+///
+/// Working out which metrics each record qualifies for, and which cohorts it belongs to, is
+/// done while filling the batch without taking any locks, so workers can do it in parallel.
+/// `StatsHistogram::merge` then only has to add the sorted values, taking each cohort's lock
+/// once per batch.
+#[derive(Default)]
+pub struct PrimingBatch {
+    /// The values to add to each metric of each cohort
+    values: HashMap<Cohort, HashMap<String, PendingValues>>,
+    /// The records behind them, in case the poller counts the account first
+    accounts: Vec<(u64, Vec<DetailedStatRecord>)>,
+    records: usize,
+}
+
+impl PrimingBatch {
+    /// How many records the batch holds
+    pub fn records(&self) -> usize {
+        self.records
     }
 }
 
+/// The histograms for every cohort, which can be updated and queried from many threads at once.
+///
+/// Writers lock one cohort at a time, and only for as long as it takes to count a single ship's
+/// stats (or, when priming, a whole batch's worth of already sorted values). Readers never lock
+/// anything: each cohort is published as an immutable snapshot every `PUBLISH_EVERY` updates and
+/// every `PUBLISH_INTERVAL`, and page loads read whichever snapshot is current.
 pub struct StatsHistogram {
-    pub ships: HashMap<u64, HashMap<String, RunningHistogram>>,
-    database_size: u64,
+    /// Only replaced when a new cohort shows up, which stops happening shortly after boot
+    cohorts: ArcSwap<HashMap<Cohort, Arc<CohortEntry>>>,
+    /// Which cohorts each ship counts towards, so we don't need to ask the ShipDb every time
+    ship_cohorts: ArcSwap<HashMap<u64, Vec<Cohort>>>,
+    shipdb: ShipDb,
+    metrics: Arc<MetricRegistry>,
+    window: Window,
+    /// When each account's stats were counted, to the millisecond (which is all Mongo keeps), so
+    /// every account is only counted once. Sharded by account ID, and held while the poller counts
+    /// an account, or priming marks one as counted, so they can't both count it.
+    accounts: Vec<Mutex<HashMap<u64, i64>>>,
 }
 
 impl StatsHistogram {
-    pub fn new() -> Self {
+    pub fn new(shipdb: ShipDb, metrics: Arc<MetricRegistry>, window: Window) -> Self {
         Self {
-            ships: HashMap::new(),
-            database_size: 100_000,
+            cohorts: ArcSwap::default(),
+            ship_cohorts: ArcSwap::default(),
+            shipdb,
+            metrics,
+            window,
+            accounts: (0..ACCOUNT_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn cohorts_of(&self, shipid: u64) -> Vec<Cohort> {
+        if let Some(x) = self.ship_cohorts.load().get(&shipid) {
+            return x.clone();
+        }
+        let cohorts = Cohort::all_for(shipid, self.shipdb.get_ship_info(shipid).as_ref());
+        // Ships the encyclopedia doesn't know about yet only have the ship and global cohorts, so
+        // check again next time
+        if cohorts.len() > 2 {
+            self.ship_cohorts.rcu(|ships| {
+                let mut ships = HashMap::clone(ships);
+                ships.insert(shipid, cohorts.clone());
+                ships
+            });
+        }
+        cohorts
+    }
+
+    fn cohort(&self, cohort: &Cohort) -> Option<Arc<CohortEntry>> {
+        self.cohorts.load().get(cohort).cloned()
+    }
+
+    fn cohort_or_insert(&self, cohort: &Cohort) -> Arc<CohortEntry> {
+        if let Some(x) = self.cohort(cohort) {
+            return x;
+        }
+        self.cohorts.rcu(|cohorts| {
+            let mut cohorts = HashMap::clone(cohorts);
+            cohorts.entry(cohort.clone()).or_default();
+            cohorts
+        });
+        self.cohorts.load()[cohort].clone()
+    }
+
+    fn account_shard(&self, account_id: u64) -> &Mutex<HashMap<u64, i64>> {
+        &self.accounts[(account_id % ACCOUNT_SHARDS) as usize]
+    }
+
+    pub fn metrics(&self) -> &MetricRegistry {
+        &self.metrics
+    }
+
+    /// Notes that a cohort has been updated, and publishes it if enough updates have piled up
+    fn updated(&self, entry: &CohortEntry, live: &mut CohortHistograms, updates: usize) {
+        live.unpublished += updates;
+        if live.unpublished >= PUBLISH_EVERY {
+            self.publish_cohort(entry, live, chrono::Utc::now());
         }
     }
 
-    pub fn set_database_size(&mut self, total_size: u64) {
-        self.database_size = total_size;
-        for (_, v) in self.ships.iter_mut() {
-            for (_, h) in v.iter_mut() {
-                h.update_db_size(total_size);
+    fn publish_cohort(
+        &self,
+        entry: &CohortEntry,
+        live: &mut CohortHistograms,
+        now: chrono::DateTime<chrono::Utc>,
+    ) {
+        entry
+            .published
+            .store(Arc::new(live.snapshot(now, self.window)));
+        live.unpublished = 0;
+    }
+
+    /// Publishes every cohort that has changed since it was last published, or whose snapshot
+    /// was weighed in an earlier epoch
+    pub fn publish(&self) {
+        let now = chrono::Utc::now();
+        let epoch = self.window.epoch_of(now);
+        for entry in self.cohorts.load().values() {
+            let mut live = entry.live.lock().unwrap();
+            if live.unpublished > 0 || entry.published.load().epoch != epoch {
+                self.publish_cohort(entry, &mut live, now);
             }
         }
     }
 
-    pub fn increment(&mut self, shipid: u64, stats: &DetailedStats) {
-        // Prevent one-off ships from skewing the data
-        let qualifies = stats.battles > 10;
+    /// Keeps publishing cohorts whose updates haven't reached page loads yet
+    pub async fn publish_loop(self: Arc<Self>) {
+        loop {
+            sleep(PUBLISH_INTERVAL).await;
+            let histograms = self.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || histograms.publish()).await {
+                error!("Couldn't publish histograms, error {:?}", e);
+            }
+        }
+    }
 
-        let stats = stats.into_map();
-        if !self.ships.contains_key(&shipid) {
-            self.ships.insert(shipid, HashMap::new());
+    /// Counts an account's freshly retrieved stats, taking back the ones they replace
+    pub fn update_account(
+        &self,
+        account_id: u64,
+        previous: &[DetailedStatRecord],
+        current: &[DetailedStatRecord],
+    ) {
+        let mut accounts = self.account_shard(account_id).lock().unwrap();
+        if let Some(counted) = accounts.remove(&account_id) {
+            for record in previous {
+                if record.retrieved.timestamp_millis() == counted {
+                    self.decrement(record.ship_id, &record.pvp, record.retrieved);
+                }
+            }
+        }
+        if let Some(record) = current.first() {
+            accounts.insert(account_id, record.retrieved.timestamp_millis());
         }
+        for record in current {
+            self.increment(record.ship_id, &record.pvp, record.retrieved);
+        }
+    }
 
-        let entry = self.ships.get_mut(&shipid).unwrap();
-        for (k, v) in stats.iter() {
-            if !entry.contains_key(k) {
-                let mut h = RunningHistogram::new(format!("{}-{}", shipid, k), initial_max_val(k));
-                h.update_db_size(self.database_size);
-                entry.insert(k.to_owned(), h);
+    /// Adds an account's stats from the database to a priming batch, unless they've already been
+    /// counted. All of the account's records must be passed at once. They're only counted once
+    /// the batch is merged.
+    pub fn prime_account(
+        &self,
+        batch: &mut PrimingBatch,
+        account_id: u64,
+        records: Vec<DetailedStatRecord>,
+    ) {
+        if self
+            .account_shard(account_id)
+            .lock()
+            .unwrap()
+            .contains_key(&account_id)
+        {
+            return;
+        }
+        for record in records.iter() {
+            // Only metrics the player has played enough battles for, so one-off ships don't skew
+            // the data
+            let stats = self.metrics.qualifying(&record.pvp);
+            if stats.is_empty() {
+                continue;
+            }
+            for cohort in self.cohorts_of(record.ship_id) {
+                let values = batch.values.entry(cohort).or_default();
+                for (k, v) in stats.iter() {
+                    if let Some(x) = values.get_mut(k) {
+                        x.push((*v, record.retrieved));
+                    } else {
+                        values.insert(k.to_owned(), vec![(*v, record.retrieved)]);
+                    }
+                }
+            }
+        }
+        batch.records += records.len();
+        batch.accounts.push((account_id, records));
+    }
+
+    /// Counts a priming batch, apart from any accounts the poller has counted since they were
+    /// added to it
+    pub fn merge(&self, batch: PrimingBatch) {
+        // The values go in first, one cohort at a time, so workers merging at once only wait on
+        // each other for the cohorts they share. Until its account is marked as counted below,
+        // the poller won't take any of them back out.
+        for (cohort, values) in batch.values.iter() {
+            let entry = self.cohort_or_insert(cohort);
+            let mut live = entry.live.lock().unwrap();
+            let mut updates = 0;
+            for (k, v) in values.iter() {
+                live.histogram(cohort, k, &self.metrics, self.window)
+                    .increment_all(v);
+                updates = updates.max(v.len());
             }
-            if qualifies {
-                entry.get_mut(k).unwrap().increment(*v);
+            self.updated(&entry, &mut live, updates);
+        }
+        for (account_id, records) in batch.accounts.iter() {
+            let mut accounts = self.account_shard(*account_id).lock().unwrap();
+            if accounts.contains_key(account_id) {
+                // The poller got there first, so take this copy back out again
+                for record in records.iter() {
+                    self.decrement(record.ship_id, &record.pvp, record.retrieved);
+                }
+            } else if let Some(record) = records.first() {
+                accounts.insert(*account_id, record.retrieved.timestamp_millis());
             }
         }
     }
 
-    pub fn get_percentiles(&self, shipid: u64, stats: &DetailedStats) -> HashMap<String, f64> {
-        let stats = stats.into_map();
-        let entry = match self.ships.get(&shipid) {
+    /// Counts a player's stats on a ship, as of when they were retrieved
+    fn increment(
+        &self,
+        shipid: u64,
+        stats: &DetailedStats,
+        retrieved: chrono::DateTime<chrono::Utc>,
+    ) {
+        // Only metrics the player has played enough battles for, so one-off ships don't skew the data
+        let stats = self.metrics.qualifying(stats);
+        self.increment_map(shipid, &stats, retrieved);
+    }
+
+    /// Takes back stats previously counted with `increment`
+    fn decrement(
+        &self,
+        shipid: u64,
+        stats: &DetailedStats,
+        retrieved: chrono::DateTime<chrono::Utc>,
+    ) {
+        let stats = self.metrics.qualifying(stats);
+        if stats.is_empty() {
+            return;
+        }
+        for cohort in self.cohorts_of(shipid) {
+            if let Some(entry) = self.cohort(&cohort) {
+                let mut live = entry.live.lock().unwrap();
+                for (k, v) in stats.iter() {
+                    if let Some(h) = live.stats.get_mut(k) {
+                        h.decrement(*v, retrieved);
+                    }
+                }
+                self.updated(&entry, &mut live, 1);
+            }
+        }
+    }
+
+    fn increment_map(
+        &self,
+        shipid: u64,
+        stats: &HashMap<String, f64>,
+        retrieved: chrono::DateTime<chrono::Utc>,
+    ) {
+        if stats.is_empty() {
+            return;
+        }
+        for cohort in self.cohorts_of(shipid) {
+            let entry = self.cohort_or_insert(&cohort);
+            let mut live = entry.live.lock().unwrap();
+            for (k, v) in stats.iter() {
+                live.histogram(&cohort, k, &self.metrics, self.window)
+                    .increment(*v, retrieved);
+            }
+            self.updated(&entry, &mut live, 1);
+        }
+    }
+
+    /// Compares the stats against the narrowest cohort with enough players in it
+    pub fn get_percentiles(&self, shipid: u64, stats: &DetailedStats) -> Percentiles {
+        self.percentiles_of(shipid, &self.metrics.extract(stats))
+    }
+
+    fn percentiles_of(&self, shipid: u64, stats: &HashMap<String, f64>) -> Percentiles {
+        let cohorts = self.cohorts.load();
+        let published: Vec<(Cohort, Arc<CohortSnapshot>)> = self
+            .cohorts_of(shipid)
+            .into_iter()
+            .filter_map(|cohort| {
+                let snapshot = cohorts.get(&cohort)?.published.load_full();
+                Some((cohort, snapshot))
+            })
+            .collect();
+        let chosen = published
+            .iter()
+            .find(|(_, snapshot)| snapshot.samples >= MIN_COHORT_SAMPLES)
+            .or_else(|| published.last());
+        let (cohort, snapshot) = match chosen {
             Some(x) => x,
-            None => return HashMap::new(),
+            None => {
+                return Percentiles {
+                    cohort: Cohort::Ship(shipid),
+                    values: HashMap::new(),
+                }
+            }
         };
-        let mut result = HashMap::new();
-        for (k, v) in stats.iter() {
-            let histogram = entry.get(k).unwrap();
-            result.insert(k.to_owned(), histogram.percentile(*v).unwrap_or(0.0));
+        let values = stats
+            .iter()
+            .filter_map(|(k, v)| {
+                let percentile = snapshot.stats.get(k)?.percentile(*v).ok()?;
+                match self.metrics.get(k) {
+                    Some(metric) if !metric.higher_is_better => {
+                        Some((k.to_owned(), 100.0 - percentile))
+                    }
+                    _ => Some((k.to_owned(), percentile)),
+                }
+            })
+            .collect();
+        Percentiles {
+            cohort: cohort.clone(),
+            values,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_histograms() -> StatsHistogram {
+        StatsHistogram::new(
+            ShipDb::new("/nonexistent"),
+            Arc::new(MetricRegistry::builtin().unwrap()),
+            Window::days(30),
+        )
+    }
+
+    #[test]
+    fn falls_back_to_broader_cohorts() {
+        let now = chrono::Utc::now();
+        let histograms = new_histograms();
+        let tier_class = Cohort::TierClass {
+            tier: 8,
+            class: "Cruiser".to_string(),
+        };
+        for ship in [1, 2] {
+            let cohorts = vec![
+                Cohort::Ship(ship),
+                tier_class.clone(),
+                Cohort::NationClass {
+                    nation: "usa".to_string(),
+                    class: "Cruiser".to_string(),
+                },
+                Cohort::Global,
+            ];
+            histograms.ship_cohorts.rcu(|ships| {
+                let mut ships = HashMap::clone(ships);
+                ships.insert(ship, cohorts.clone());
+                ships
+            });
+        }
+
+        let stats = |damage: f64| {
+            let mut m = HashMap::new();
+            m.insert("damage_dealt".to_string(), damage);
+            m
+        };
+        for i in 0..MIN_COHORT_SAMPLES {
+            histograms.increment_map(1, &stats(i as f64 * 1000.0), now);
+        }
+        histograms.increment_map(2, &stats(50_000.0), now);
+        histograms.publish();
+
+        // Ship 1 has plenty of players, ship 2 is compared with the rest of its tier and class
+        assert_eq!(
+            histograms.percentiles_of(1, &stats(0.0)).cohort,
+            Cohort::Ship(1)
+        );
+        let percentiles = histograms.percentiles_of(2, &stats(25_000.0));
+        assert_eq!(percentiles.cohort, tier_class);
+        assert!(percentiles.values["damage_dealt"] > 0.0);
+
+        // Ships we've never seen at all still get a global comparison
+        assert_eq!(
+            histograms.percentiles_of(3, &stats(0.0)).cohort,
+            Cohort::Global
+        );
+    }
+
+    #[test]
+    fn lower_is_better_metrics_are_inverted() {
+        let now = chrono::Utc::now();
+        let histograms = new_histograms();
+        let stats = |losses: f64| {
+            let mut m = HashMap::new();
+            m.insert("losses".to_string(), losses);
+            m.insert("winrate".to_string(), 1.0 - losses);
+            m
+        };
+        for i in 0..100 {
+            histograms.increment_map(1, &stats(i as f64 / 100.0), now);
+        }
+        histograms.publish();
+
+        // Losing rarely beats most players, as does winning often
+        let percentiles = histograms.percentiles_of(1, &stats(0.1));
+        assert!(percentiles.values["losses"] > 80.0);
+        assert!(percentiles.values["winrate"] > 80.0);
+
+        // Metrics that couldn't be computed aren't reported at all
+        let mut nan = stats(0.1);
+        nan.insert("losses".to_string(), f64::NAN);
+        assert!(!histograms
+            .percentiles_of(1, &nan)
+            .values
+            .contains_key("losses"));
+    }
+
+    #[test]
+    fn readers_dont_wait_for_writers() {
+        let now = chrono::Utc::now();
+        let histograms = new_histograms();
+        let stats = |damage: f64| {
+            let mut m = HashMap::new();
+            m.insert("damage_dealt".to_string(), damage);
+            m
+        };
+        for i in 0..100 {
+            histograms.increment_map(1, &stats(i as f64 * 1000.0), now);
+        }
+        histograms.publish();
+
+        // A writer is halfway through updating both cohorts. Locking either of them again from
+        // this thread would deadlock.
+        let ship = histograms.cohort(&Cohort::Ship(1)).unwrap();
+        let global = histograms.cohort(&Cohort::Global).unwrap();
+        let _ship = ship.live.lock().unwrap();
+        let _global = global.live.lock().unwrap();
+        let percentiles = histograms.percentiles_of(1, &stats(50_000.0));
+        assert_eq!(percentiles.cohort, Cohort::Ship(1));
+        assert!((50.0..51.0).contains(&percentiles.values["damage_dealt"]));
+    }
+
+    #[test]
+    fn unpublished_updates_are_published_in_bulk() {
+        let now = chrono::Utc::now();
+        let histograms = new_histograms();
+        let mut stats = HashMap::new();
+        stats.insert("damage_dealt".to_string(), 1000.0);
+        let samples = |histograms: &StatsHistogram| {
+            histograms
+                .cohort(&Cohort::Ship(1))
+                .unwrap()
+                .published
+                .load()
+                .samples
+        };
+
+        histograms.increment_map(1, &stats, now);
+        assert_eq!(samples(&histograms), 0);
+        for _ in 1..PUBLISH_EVERY {
+            histograms.increment_map(1, &stats, now);
+        }
+        assert_eq!(samples(&histograms), PUBLISH_EVERY as u64);
+
+        // Anything short of that waits for the next round of publishing
+        histograms.increment_map(1, &stats, now);
+        assert_eq!(samples(&histograms), PUBLISH_EVERY as u64);
+        histograms.publish();
+        assert_eq!(samples(&histograms), PUBLISH_EVERY as u64 + 1);
+    }
+
+    fn record(
+        account_id: u64,
+        damage: u32,
+        retrieved: chrono::DateTime<chrono::Utc>,
+    ) -> DetailedStatRecord {
+        let mut pvp = DetailedStats::zeroed();
+        pvp.battles = 100;
+        pvp.wins = 50;
+        pvp.losses = 50;
+        pvp.damage_dealt = damage * 100;
+        DetailedStatRecord {
+            pvp,
+            account_id,
+            ship_id: 1,
+            battles: 100,
+            retrieved,
+        }
+    }
+
+    fn prime(histograms: &StatsHistogram, account_id: u64, records: &[DetailedStatRecord]) {
+        let mut batch = PrimingBatch::default();
+        histograms.prime_account(&mut batch, account_id, records.to_vec());
+        histograms.merge(batch);
+    }
+
+    fn samples(histograms: &StatsHistogram, cohort: &Cohort) -> u64 {
+        histograms
+            .cohort(cohort)
+            .unwrap()
+            .live
+            .lock()
+            .unwrap()
+            .stats
+            .values()
+            .map(|h| h.len(chrono::Utc::now()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn accounts_are_only_counted_once() {
+        let histograms = new_histograms();
+        let ship = Cohort::Ship(1);
+        let scraped = chrono::Utc::now() - chrono::Duration::days(2);
+        let first = vec![record(1, 30_000, scraped)];
+        prime(&histograms, 1, &first);
+        prime(&histograms, 2, &[record(2, 50_000, scraped)]);
+        assert_eq!(samples(&histograms, &ship), 2);
+
+        // The poller comes back around to the first account
+        let second = vec![record(1, 40_000, scraped + chrono::Duration::days(1))];
+        histograms.update_account(1, &first, &second);
+        assert_eq!(samples(&histograms, &ship), 2);
+
+        // Priming afterwards (e.g. the poller beat it to the account) doesn't count it again
+        prime(&histograms, 1, &second);
+        assert_eq!(samples(&histograms, &ship), 2);
+
+        // Neither does the poller counting it while it's waiting in a priming batch
+        let mut batch = PrimingBatch::default();
+        histograms.prime_account(&mut batch, 3, vec![record(3, 35_000, scraped)]);
+        let rescraped = vec![record(3, 20_000, scraped + chrono::Duration::days(1))];
+        histograms.update_account(3, &[], &rescraped);
+        histograms.merge(batch);
+        assert_eq!(samples(&histograms, &ship), 3);
+
+        // Or looking the player up twice in a row
+        let third = vec![record(1, 45_000, scraped + chrono::Duration::days(1))];
+        histograms.update_account(1, &second, &third);
+        assert_eq!(samples(&histograms, &ship), 3);
+        histograms.publish();
+        let percentiles = histograms.percentiles_of(1, &histograms.metrics.extract(&third[0].pvp));
+        assert_eq!(percentiles.values["damage_dealt"].round(), 50.0);
+    }
+
+    #[test]
+    fn priming_and_updates_can_run_in_parallel() {
+        let histograms = new_histograms();
+        let scraped = chrono::Utc::now() - chrono::Duration::days(2);
+        let rescraped = scraped + chrono::Duration::days(1);
+        std::thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let histograms = &histograms;
+                scope.spawn(move || {
+                    // Two threads prime every account from the database, while the other two
+                    // re-scrape half of the accounts each
+                    let mut batch = PrimingBatch::default();
+                    for account in 0..500 {
+                        let old = vec![record(account, 30_000, scraped)];
+                        if thread < 2 {
+                            histograms.prime_account(&mut batch, account, old);
+                            if batch.records() >= 50 {
+                                histograms.merge(std::mem::take(&mut batch));
+                            }
+                        } else if account % 2 == thread % 2 {
+                            let new = vec![record(account, 40_000, rescraped)];
+                            histograms.update_account(account, &old, &new);
+                        }
+                    }
+                    histograms.merge(batch);
+                });
+            }
+        });
+        assert_eq!(samples(&histograms, &Cohort::Ship(1)), 500);
+        assert_eq!(samples(&histograms, &Cohort::Global), 500);
+    }
+
+    #[test]
+    fn priming_workers_only_lock_to_merge() {
+        let histograms = new_histograms();
+        let scraped = chrono::Utc::now() - chrono::Duration::days(2);
+        prime(&histograms, 0, &[record(0, 30_000, scraped)]);
+
+        // Even with the busiest cohort locked, a worker can fill its batch, which is most of the
+        // work of priming
+        let global = histograms.cohort(&Cohort::Global).unwrap();
+        let locked = global.live.lock().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let histograms = &histograms;
+            let worker = scope.spawn(move || {
+                let mut batch = PrimingBatch::default();
+                for account in 1..1_000 {
+                    histograms.prime_account(
+                        &mut batch,
+                        account,
+                        vec![record(account, 30_000, scraped)],
+                    );
+                }
+                sender.send(batch.records()).unwrap();
+                histograms.merge(batch);
+            });
+            let filled = receiver.recv_timeout(std::time::Duration::from_secs(60));
+            drop(locked);
+            assert_eq!(filled, Ok(999));
+            worker.join().unwrap();
+        });
+        assert_eq!(samples(&histograms, &Cohort::Global), 1_000);
+    }
+
+    /// Priming throughput with different numbers of workers. Run it on a machine with a few cores:
+    /// cargo test --release bench_priming_scaling -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_priming_scaling() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let scraped = chrono::Utc::now() - chrono::Duration::days(2);
+        let accounts: Vec<Vec<DetailedStatRecord>> = (0..4_000)
+            .map(|account| {
+                (0..50)
+                    .map(|_| {
+                        let mut record = record(account, rng.gen_range(10_000..100_000), scraped);
+                        record.ship_id = rng.gen_range(0..300);
+                        record.pvp.frags = rng.gen_range(0..200);
+                        record
+                    })
+                    .collect()
+            })
+            .collect();
+        let records: usize = accounts.iter().map(|x| x.len()).sum();
+
+        let cores = std::thread::available_parallelism()
+            .map(|x| x.get())
+            .unwrap_or(1);
+        for workers in [1, 2, 4, 8] {
+            let histograms = new_histograms();
+            let mut shares: Vec<Vec<Vec<DetailedStatRecord>>> = vec![vec![]; workers];
+            for (i, account) in accounts.iter().enumerate() {
+                shares[i % workers].push(account.clone());
+            }
+            let start = std::time::Instant::now();
+            // Merging is the only part of priming that workers take turns at
+            let merging: std::time::Duration = std::thread::scope(|scope| {
+                let workers: Vec<_> = shares
+                    .into_iter()
+                    .map(|share| {
+                        let histograms = &histograms;
+                        scope.spawn(move || {
+                            let mut merging = std::time::Duration::ZERO;
+                            let mut merge = |batch| {
+                                let start = std::time::Instant::now();
+                                histograms.merge(batch);
+                                merging += start.elapsed();
+                            };
+                            let mut batch = PrimingBatch::default();
+                            for account in share {
+                                let account_id = account[0].account_id;
+                                histograms.prime_account(&mut batch, account_id, account);
+                                if batch.records() >= PRIMING_BATCH_RECORDS {
+                                    merge(std::mem::take(&mut batch));
+                                }
+                            }
+                            merge(batch);
+                            merging
+                        })
+                    })
+                    .collect();
+                workers.into_iter().map(|x| x.join().unwrap()).sum()
+            });
+            let elapsed = start.elapsed();
+            assert_eq!(samples(&histograms, &Cohort::Global), records as u64);
+            println!(
+                "{} workers on {} cores: {} records in {:?}, {:.0} records/s, {:.0}% of worker time merging",
+                workers,
+                cores,
+                records,
+                elapsed,
+                records as f64 / elapsed.as_secs_f64(),
+                100.0 * merging.as_secs_f64() / (elapsed.as_secs_f64() * workers as f64)
+            );
         }
-        result
     }
 }
@@ -0,0 +1,205 @@
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::wows_data::{DetailedStats, ShipInfo};
+
+/// How many ships to list as most played and best performing
+const TOP_SHIPS: usize = 5;
+
+/// The metrics whose percentiles are averaged to rank a player's ships
+const RANKING_METRICS: [&str; 3] = ["damage_dealt", "winrate", "frags"];
+
+/// One of the player's ships, as it goes into the summary
+pub struct ShipEntry<'a> {
+    pub ship_id: u64,
+    pub info: Option<&'a ShipInfo>,
+    pub stats: &'a DetailedStats,
+    pub percentiles: &'a HashMap<String, f64>,
+    pub reliable: bool,
+}
+
+/// Totals over a group of the player's ships, so ships count in proportion to their battles
+#[derive(Debug, Default, Serialize)]
+pub struct Aggregate {
+    pub battles: u64,
+    pub winrate: f64,
+    pub damage_dealt: f64,
+    pub frags: f64,
+    pub survival_rate: f64,
+    #[serde(skip)]
+    wins: u64,
+    #[serde(skip)]
+    total_damage: u64,
+    #[serde(skip)]
+    total_frags: u64,
+    #[serde(skip)]
+    survived: u64,
+}
+
+impl Aggregate {
+    fn add(&mut self, stats: &DetailedStats) {
+        self.battles += stats.battles as u64;
+        self.wins += stats.wins as u64;
+        self.total_damage += stats.damage_dealt as u64;
+        self.total_frags += stats.frags as u64;
+        self.survived += stats.survived_battles as u64;
+    }
+
+    fn finish(&mut self) {
+        if self.battles == 0 {
+            return;
+        }
+        let battles = self.battles as f64;
+        self.winrate = self.wins as f64 / battles;
+        self.damage_dealt = self.total_damage as f64 / battles;
+        self.frags = self.total_frags as f64 / battles;
+        self.survival_rate = self.survived as f64 / battles;
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShipRef {
+    pub ship_id: u64,
+    pub name: Option<String>,
+    pub battles: u32,
+    /// Average percentile over the ranking metrics
+    pub score: Option<f64>,
+}
+
+/// Account-wide totals across all of a player's ships
+#[derive(Debug, Serialize)]
+pub struct AccountSummary {
+    pub overall: Aggregate,
+    pub by_class: BTreeMap<String, Aggregate>,
+    pub by_tier: BTreeMap<u64, Aggregate>,
+    pub by_nation: BTreeMap<String, Aggregate>,
+    pub most_played: Vec<ShipRef>,
+    /// Only ships with enough battles for their percentiles to mean something
+    pub best_performing: Vec<ShipRef>,
+}
+
+fn score(percentiles: &HashMap<String, f64>) -> Option<f64> {
+    let values: Vec<f64> = RANKING_METRICS
+        .iter()
+        .filter_map(|key| percentiles.get(*key).copied())
+        .collect();
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+impl AccountSummary {
+    pub fn new(ships: &[ShipEntry]) -> Self {
+        let mut overall = Aggregate::default();
+        let mut by_class: BTreeMap<String, Aggregate> = BTreeMap::new();
+        let mut by_tier: BTreeMap<u64, Aggregate> = BTreeMap::new();
+        let mut by_nation: BTreeMap<String, Aggregate> = BTreeMap::new();
+        for ship in ships {
+            overall.add(ship.stats);
+            if let Some(info) = ship.info {
+                by_class
+                    .entry(info.ship_type.clone())
+                    .or_default()
+                    .add(ship.stats);
+                by_tier.entry(info.tier).or_default().add(ship.stats);
+                by_nation
+                    .entry(info.nation.clone())
+                    .or_default()
+                    .add(ship.stats);
+            }
+        }
+        overall.finish();
+        for aggregate in by_class
+            .values_mut()
+            .chain(by_tier.values_mut())
+            .chain(by_nation.values_mut())
+        {
+            aggregate.finish();
+        }
+
+        let to_ref = |ship: &ShipEntry| ShipRef {
+            ship_id: ship.ship_id,
+            name: ship.info.map(|x| x.name.clone()),
+            battles: ship.stats.battles,
+            score: score(ship.percentiles),
+        };
+
+        let mut most_played: Vec<&ShipEntry> = ships.iter().collect();
+        most_played.sort_by_key(|ship| std::cmp::Reverse(ship.stats.battles));
+        let most_played = most_played
+            .into_iter()
+            .take(TOP_SHIPS)
+            .map(to_ref)
+            .collect();
+
+        let mut best_performing: Vec<ShipRef> = ships
+            .iter()
+            .filter(|ship| ship.reliable)
+            .map(to_ref)
+            .filter(|ship| ship.score.is_some())
+            .collect();
+        best_performing.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        best_performing.truncate(TOP_SHIPS);
+
+        Self {
+            overall,
+            by_class,
+            by_tier,
+            by_nation,
+            most_played,
+            best_performing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(battles: u32, wins: u32, damage: u32) -> DetailedStats {
+        let mut stats = DetailedStats::zeroed();
+        stats.battles = battles;
+        stats.wins = wins;
+        stats.damage_dealt = damage * battles;
+        stats
+    }
+
+    #[test]
+    fn aggregates_are_weighted_by_battles() {
+        let played_a_lot = stats(900, 540, 50_000);
+        let barely_played = stats(100, 10, 10_000);
+        let mut good = HashMap::new();
+        good.insert("winrate".to_string(), 90.0);
+        let mut bad = HashMap::new();
+        bad.insert("winrate".to_string(), 5.0);
+        let ships = [
+            ShipEntry {
+                ship_id: 1,
+                info: None,
+                stats: &barely_played,
+                percentiles: &good,
+                reliable: false,
+            },
+            ShipEntry {
+                ship_id: 2,
+                info: None,
+                stats: &played_a_lot,
+                percentiles: &bad,
+                reliable: true,
+            },
+        ];
+        let summary = AccountSummary::new(&ships);
+        assert_eq!(summary.overall.battles, 1_000);
+        assert!((summary.overall.winrate - 0.55).abs() < 1e-9);
+        assert!((summary.overall.damage_dealt - 46_000.0).abs() < 1e-9);
+        assert_eq!(summary.most_played[0].ship_id, 2);
+        // The first ship looks great, but not with so few battles
+        assert_eq!(summary.best_performing.len(), 1);
+        assert_eq!(summary.best_performing[0].ship_id, 2);
+    }
+}
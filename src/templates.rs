@@ -0,0 +1,205 @@
+use rocket::http::Status;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tera::{Context, Tera};
+use tracing::*;
+
+/// Every template we serve, along with the copy built into the binary. A file of the same name in
+/// `templates/` takes precedence, so templates can be tweaked without a rebuild.
+const TEMPLATES: &[(&str, &str)] = &[
+    (
+        "cheatsheet.html",
+        std::include_str!("../templates/cheatsheet.html"),
+    ),
+    (
+        "playerstats.txt",
+        std::include_str!("../templates/playerstats.txt"),
+    ),
+    (
+        "realmstats.txt",
+        std::include_str!("../templates/realmstats.txt"),
+    ),
+];
+
+const TEMPLATE_DIR: &str = "templates";
+
+/// The template engine, shared between all requests
+pub struct Templates {
+    tera: RwLock<Tera>,
+    /// Re-read the templates from disk on every render, for working on them
+    live_reload: bool,
+}
+
+fn shortclass(class: &tera::Value, _: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let class: crate::cheatsheet::ShipClass = serde_json::value::from_value(class.clone())?;
+    Ok(class.short().into())
+}
+
+/// Floats which might be null, which are shown as zero
+fn unwrap_float(
+    value: &tera::Value,
+    _: &HashMap<String, tera::Value>,
+) -> tera::Result<tera::Value> {
+    let value: Option<f32> = serde_json::value::from_value(value.clone())?;
+    Ok(value.unwrap_or(0.0).into())
+}
+
+fn mult100(value: &tera::Value, _: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let value: f32 = serde_json::value::from_value(value.clone())?;
+    Ok((value * 100.0).into())
+}
+
+fn none(value: Option<&tera::Value>, _: &[tera::Value]) -> tera::Result<bool> {
+    Ok(value.map(|x| x.is_null()).unwrap_or(true))
+}
+
+fn build() -> tera::Result<Tera> {
+    let mut tera = Tera::default();
+    for (name, builtin) in TEMPLATES.iter() {
+        let path = format!("{}/{}", TEMPLATE_DIR, name);
+        let source = std::fs::read_to_string(&path).unwrap_or_else(|_| builtin.to_string());
+        tera.add_raw_template(name, &source)?;
+    }
+    tera.register_filter("shortclass", shortclass);
+    tera.register_filter("unwrap_float", unwrap_float);
+    tera.register_filter("mult100", mult100);
+    tera.register_tester("none", none);
+    Ok(tera)
+}
+
+impl Templates {
+    pub fn new(live_reload: bool) -> tera::Result<Self> {
+        Ok(Templates {
+            tera: RwLock::new(build()?),
+            live_reload,
+        })
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, Status> {
+        // Renders only need to read the templates, so they can all go at once. The write lock is
+        // only taken to swap in freshly reloaded templates.
+        if self.live_reload {
+            match build() {
+                Ok(reloaded) => *self.tera.write().unwrap() = reloaded,
+                Err(e) => {
+                    error!("Couldn't reload templates: {:?}", e);
+                    return Err(Status::InternalServerError);
+                }
+            }
+        }
+        let tera = self.tera.read().unwrap();
+        tera.render(name, context).map_err(|e| {
+            error!("Couldn't render {}: {:?}", name, e);
+            Status::InternalServerError
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_templates_parse_and_filters_work() {
+        let templates = Templates::new(false).unwrap();
+        let mut context = Context::new();
+        context.insert("value", &0.25);
+        context.insert("missing", &Option::<f32>::None);
+
+        let mut tera = templates.tera.read().unwrap().clone();
+        tera.add_raw_template(
+            "test",
+            "{{ value | mult100 }} {{ missing | unwrap_float }} {{ missing is none }} {{ \"Cruiser\" | shortclass }}",
+        )
+        .unwrap();
+        assert_eq!(tera.render("test", &context).unwrap(), "25 0 true CR");
+    }
+
+    #[test]
+    fn playerstats_renders_with_partial_stats() {
+        let templates = Templates::new(false).unwrap();
+        let context = Context::from_serialize(serde_json::json!({
+            "error": false,
+            "username": "someone",
+            "data_age": "just now",
+            "analysis": {
+                "strengths": [{"text": "Damage: better than 80% of players, over 500 battles"}],
+                "weaknesses": [],
+                "patterns": [],
+            },
+            "summary": {
+                "overall": {"battles": 12, "winrate": 0.5, "damage_dealt": 30000.0, "frags": 0.5, "survival_rate": 0.25},
+                "by_class": {"Cruiser": {"battles": 12, "winrate": 0.5, "damage_dealt": 30000.0, "frags": 0.5, "survival_rate": 0.25}},
+                "by_tier": {"8": {"battles": 12, "winrate": 0.5, "damage_dealt": 30000.0, "frags": 0.5, "survival_rate": 0.25}},
+                "by_nation": {},
+                "most_played": [{"ship_id": 1, "name": "Baltimore", "battles": 12, "score": null}, {"ship_id": 2, "name": null, "battles": 3, "score": null}],
+                "best_performing": [],
+            },
+            "ships": [{
+                "known": true,
+                "tier": 8,
+                "nation": "usa",
+                "ship_type": "Cruiser",
+                "name": "Baltimore",
+                "num_battles": 12,
+                "shipid": 1,
+                "cohort": "players on this ship",
+                "reliable": false,
+                "stats": {"winrate": 0.5},
+                "percentiles": {},
+                "intervals": {"winrate": {"low": 0.25, "high": 0.75}},
+            }],
+        }))
+        .unwrap();
+        let page = templates.render("playerstats.txt", &context).unwrap();
+        assert!(page.contains("Winrate: 50% (95% CI 25%-75%)"), "{}", page);
+        assert!(page.contains("grain of salt"));
+        assert!(page.contains("Damage dealt: 0 (better than"));
+        assert!(
+            page.contains("- Tier 8: 12 battles, 50% winrate"),
+            "{}",
+            page
+        );
+        assert!(
+            page.contains("Most played: Baltimore (12), 2 (3)"),
+            "{}",
+            page
+        );
+    }
+
+    #[test]
+    fn realmstats_renders_before_and_after_the_first_aggregation() {
+        let templates = Templates::new(false).unwrap();
+        let mut context = Context::new();
+        context.insert("stats", &Option::<()>::None);
+        let page = templates.render("realmstats.txt", &context).unwrap();
+        assert!(page.contains("haven't been computed yet"));
+
+        let context = Context::from_serialize(serde_json::json!({
+            "min_balance_players": 100,
+            "stats": {
+                "computed": "2021-06-01T00:00:00Z",
+                "accounts": 2,
+                "ships_tracked": 1,
+                "player_ship_records": 2,
+                "battles": 300,
+                "battles_by_class": [{"key": "Cruiser", "battles": 300}],
+                "battles_by_tier": [{"key": "8", "battles": 300}],
+                "battles_by_nation": [{"key": "usa", "battles": 300}],
+                "most_played": [{"ship_id": 1, "name": "Baltimore", "tier": 8, "ship_type": "Cruiser", "players": 2, "battles": 300, "winrate": 0.5}],
+                "ship_balance": [{"ship_id": 1, "name": null, "tier": null, "ship_type": null, "players": 2, "battles": 300, "winrate": 0.5}],
+                "account_sizes": [{"label": "100-499", "accounts": 2}],
+            },
+        }))
+        .unwrap();
+        let page = templates.render("realmstats.txt", &context).unwrap();
+        assert!(page.contains("- Tier 8: 300"), "{}", page);
+        assert!(
+            page.contains("- Baltimore: 300 battles by 2 players"),
+            "{}",
+            page
+        );
+        assert!(page.contains("1. 1: 50% over 300 battles"), "{}", page);
+        assert!(page.contains("- 100-499: 2 accounts"), "{}", page);
+    }
+}
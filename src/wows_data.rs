@@ -25,7 +25,12 @@ pub struct GenericReply<T> {
     pub data: Option<T>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize)]
+pub struct EncyclopediaInfo {
+    pub game_version: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MobilityProfile {
     pub rudder_time: f32,
     pub total: f32,
@@ -33,7 +38,7 @@ pub struct MobilityProfile {
     pub max_speed: f32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TorpedoesProfile {
     #[serde(rename = "torpedoes_id")]
     pub id: u64,
@@ -47,7 +52,7 @@ pub struct TorpedoesProfile {
     pub max_damage: u32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DetailedModuleInfoTorpedoProfile {
     pub torpedo_speed: u64,
     pub shot_speed: f32,
@@ -55,12 +60,12 @@ pub struct DetailedModuleInfoTorpedoProfile {
     pub distance: f32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DetailedModuleInfoProfile {
     pub torpedoes: Option<DetailedModuleInfoTorpedoProfile>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DetailedModuleInfo {
     pub module_id: u64,
     #[serde(rename = "type")]
@@ -68,7 +73,7 @@ pub struct DetailedModuleInfo {
     pub profile: DetailedModuleInfoProfile,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ShipProfile {
     pub mobility: Option<MobilityProfile>,
     pub torpedoes: Option<TorpedoesProfile>,
@@ -76,7 +81,7 @@ pub struct ShipProfile {
     pub battle_level_range_min: Option<u16>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ModuleInfo {
     pub name: String,
     //next_modules
@@ -93,7 +98,7 @@ pub struct ModuleInfo {
     pub module_id_str: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ShipInfo {
     pub description: String,
     pub price_gold: u32,
@@ -117,6 +122,20 @@ pub struct ShipInfo {
     pub name: String,
 }
 
+impl ShipInfo {
+    /// The longest torpedo range across all of the ship's torpedo modules, in km
+    pub fn torpedo_range(&self, modules: &HashMap<u64, DetailedModuleInfo>) -> Option<f32> {
+        self.modules_tree
+            .values()
+            .filter_map(|module_info| modules.get(&module_info.module_id))
+            .filter_map(|module| module.profile.torpedoes.as_ref())
+            .map(|torpedoes| torpedoes.distance)
+            .fold(None, |range: Option<f32>, distance| {
+                Some(range.map(|x| x.max(distance)).unwrap_or(distance))
+            })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BatteryStats {
     pub max_frags_battle: u8,
@@ -129,22 +148,6 @@ pub struct BatteryStats {
     pub shots: u32,
 }
 
-impl BatteryStats {
-    pub fn into_map(&self, m: &mut HashMap<String, f64>, prefix: &str, nbattles: f64) {
-        m.insert(
-            format!("{}.max_frags_battle", prefix),
-            self.max_frags_battle as f64,
-        );
-        m.insert(format!("{}.frags", prefix), self.frags as f64 / nbattles);
-        m.insert(format!("{}.hits", prefix), self.hits as f64 / nbattles);
-        m.insert(format!("{}.shots", prefix), self.shots as f64 / nbattles);
-        m.insert(
-            format!("{}.hitrate", prefix),
-            self.hits as f64 / self.shots as f64,
-        );
-    }
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DetailedStats {
     pub max_xp: u32,
@@ -185,104 +188,54 @@ pub struct DetailedStats {
     pub battles_since_512: u32,
 }
 
+#[cfg(test)]
 impl DetailedStats {
-    pub fn into_map(&self) -> HashMap<String, f64> {
-        let nbattles = self.battles as f64;
-
-        let mut m = HashMap::new();
-        self.main_battery.into_map(&mut m, "main_battery", nbattles);
-        self.second_battery
-            .into_map(&mut m, "second_battery", nbattles);
-        self.ramming.into_map(&mut m, "ramming", nbattles);
-        self.torpedoes.into_map(&mut m, "torpedoes", nbattles);
-        self.aircraft.into_map(&mut m, "aircraft", nbattles);
-        m.insert("max_xp".to_owned(), self.max_xp as f64);
-        m.insert(
-            "damage_to_buildings".to_owned(),
-            self.damage_to_buildings as f64,
-        );
-        m.insert(
-            "suppressions_count".to_owned(),
-            self.suppressions_count as f64,
-        );
-        m.insert(
-            "max_damage_scouting".to_owned(),
-            self.max_damage_scouting as f64,
-        );
-        m.insert("art_agro".to_owned(), self.art_agro as f64);
-        m.insert(
-            "ships_spotted".to_owned(),
-            self.ships_spotted as f64 / nbattles,
-        );
-        m.insert("xp".to_owned(), self.xp as f64 / nbattles);
-        m.insert("survived_battles".to_owned(), self.survived_battles as f64);
-        m.insert(
-            "dropped_capture_points".to_owned(),
-            self.dropped_capture_points as f64 / nbattles,
-        );
-        m.insert(
-            "max_damage_dealt_to_buildings".to_owned(),
-            self.max_damage_dealt_to_buildings as f64,
-        );
-        m.insert("torpedo_agro".to_owned(), self.torpedo_agro as f64);
-        m.insert("draws".to_owned(), self.draws as f64);
-        m.insert(
-            "battles_since_510".to_owned(),
-            self.battles_since_510 as f64,
-        );
-        m.insert(
-            "planes_killed".to_owned(),
-            self.planes_killed as f64 / nbattles,
-        );
-        m.insert("battles".to_owned(), self.battles as f64);
-        m.insert(
-            "max_ships_spotted".to_owned(),
-            self.max_ships_spotted as f64,
-        );
-        m.insert(
-            "team_capture_points".to_owned(),
-            self.team_capture_points as f64 / nbattles,
-        );
-        m.insert("frags".to_owned(), self.frags as f64 / nbattles);
-        m.insert(
-            "damage_scouting".to_owned(),
-            self.damage_scouting as f64 / nbattles,
-        );
-        m.insert("max_total_agro".to_owned(), self.max_total_agro as f64);
-        m.insert("max_frags_battle".to_owned(), self.max_frags_battle as f64);
-        m.insert(
-            "capture_points".to_owned(),
-            self.capture_points as f64 / nbattles,
-        );
-        m.insert(
-            "win_surviverate".to_owned(),
-            self.survived_wins as f64 / self.wins as f64,
-        );
-        m.insert("max_damage_dealt".to_owned(), self.max_damage_dealt as f64);
-        m.insert("wins".to_owned(), self.wins as f64);
-        m.insert("winrate".to_owned(), self.wins as f64 / nbattles);
-        m.insert("losses".to_owned(), self.losses as f64 / nbattles);
-        m.insert(
-            "damage_dealt".to_owned(),
-            self.damage_dealt as f64 / nbattles,
-        );
-        m.insert(
-            "max_planes_killed".to_owned(),
-            self.max_planes_killed as f64,
-        );
-        m.insert(
-            "max_suppressions_count".to_owned(),
-            self.max_suppressions_count as f64,
-        );
-        m.insert(
-            "team_dropped_capture_points".to_owned(),
-            self.team_dropped_capture_points as f64,
-        );
-        m.insert(
-            "battles_since_512".to_owned(),
-            self.battles_since_512 as f64,
-        );
-        m
+    /// Stats for a player who hasn't done anything yet, to fill in for tests
+    pub fn zeroed() -> Self {
+        let battery = BatteryStats {
+            max_frags_battle: 0,
+            frags: 0,
+            hits: 0,
+            shots: 0,
+        };
+        Self {
+            max_xp: 0,
+            damage_to_buildings: 0,
+            main_battery: battery.clone(),
+            suppressions_count: 0,
+            max_damage_scouting: 0,
+            art_agro: 0,
+            ships_spotted: 0,
+            second_battery: battery.clone(),
+            xp: 0,
+            survived_battles: 0,
+            dropped_capture_points: 0,
+            max_damage_dealt_to_buildings: 0,
+            torpedo_agro: 0,
+            draws: 0,
+            battles_since_510: 0,
+            planes_killed: 0,
+            battles: 0,
+            max_ships_spotted: 0,
+            team_capture_points: 0,
+            frags: 0,
+            damage_scouting: 0,
+            max_total_agro: 0,
+            max_frags_battle: 0,
+            capture_points: 0,
+            ramming: battery.clone(),
+            torpedoes: battery.clone(),
+            aircraft: battery,
+            survived_wins: 0,
+            max_damage_dealt: 0,
+            wins: 0,
+            losses: 0,
+            damage_dealt: 0,
+            max_planes_killed: 0,
+            max_suppressions_count: 0,
+            team_dropped_capture_points: 0,
+            battles_since_512: 0,
+        }
     }
 }
 